@@ -1,6 +1,6 @@
 use rand::{rng, seq::IteratorRandom};
 use std::{
-    cmp::Reverse,
+    cmp::{Ordering, Reverse},
     collections::{BinaryHeap, VecDeque},
 };
 
@@ -19,35 +19,99 @@ impl Algorithm for BreadthFirst {
     fn next(&mut self) -> Option<PathfinderTile> {
         self.queue.pop_front()
     }
+
+    fn frontier(&self) -> Vec<(PathfinderTile, Option<f32>)> {
+        self.queue.iter().map(|&tile| (tile, None)).collect()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Dijkstra {
+    queue: BinaryHeap<Cheapest>,
+}
+
+impl Algorithm for Dijkstra {
+    fn insert(&mut self, tile: PathfinderTile) {
+        self.queue.push(Cheapest(tile, tile.cost));
+    }
+
+    fn next(&mut self) -> Option<PathfinderTile> {
+        self.queue.pop().map(|cheapest| cheapest.0)
+    }
+
+    fn frontier(&self) -> Vec<(PathfinderTile, Option<f32>)> {
+        drain_heap(self.queue.clone())
+            .map(|Cheapest(tile, cost)| (tile, Some(cost)))
+            .collect()
+    }
 }
 
 #[derive(Debug, Default)]
 struct AStar {
-    queue: BinaryHeap<PathfinderTile>,
+    queue: BinaryHeap<Cheapest>,
 }
 
 impl Algorithm for AStar {
     fn insert(&mut self, tile: PathfinderTile) {
-        self.queue.push(tile);
+        self.queue.push(Cheapest(tile, tile.score()));
     }
 
     fn next(&mut self) -> Option<PathfinderTile> {
-        self.queue.pop()
+        self.queue.pop().map(|cheapest| cheapest.0)
+    }
+
+    fn frontier(&self) -> Vec<(PathfinderTile, Option<f32>)> {
+        drain_heap(self.queue.clone())
+            .map(|Cheapest(tile, score)| (tile, Some(score)))
+            .collect()
     }
 }
 
 #[derive(Debug, Default)]
 struct ReverseAStar {
-    queue: BinaryHeap<Reverse<PathfinderTile>>,
+    queue: BinaryHeap<Reverse<Cheapest>>,
 }
 
 impl Algorithm for ReverseAStar {
     fn insert(&mut self, tile: PathfinderTile) {
-        self.queue.push(Reverse(tile));
+        self.queue.push(Reverse(Cheapest(tile, tile.score())));
     }
 
     fn next(&mut self) -> Option<PathfinderTile> {
-        self.queue.pop().map(|title| title.0)
+        self.queue.pop().map(|Reverse(cheapest)| cheapest.0)
+    }
+
+    fn frontier(&self) -> Vec<(PathfinderTile, Option<f32>)> {
+        drain_heap(self.queue.clone())
+            .map(|Reverse(Cheapest(tile, score))| (tile, Some(score)))
+            .collect()
+    }
+}
+
+// Orders by the priority it was queued with, lowest first, ties go to the tile closest to a goal
+#[derive(Debug, Clone)]
+struct Cheapest(PathfinderTile, f32);
+
+impl PartialEq for Cheapest {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Cheapest {}
+
+impl PartialOrd for Cheapest {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Cheapest {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .1
+            .total_cmp(&self.1)
+            .then_with(|| self.0.cmp(&other.0))
     }
 }
 
@@ -64,6 +128,10 @@ impl Algorithm for DepthFirst {
     fn next(&mut self) -> Option<PathfinderTile> {
         self.queue.pop()
     }
+
+    fn frontier(&self) -> Vec<(PathfinderTile, Option<f32>)> {
+        self.queue.iter().rev().map(|&tile| (tile, None)).collect()
+    }
 }
 
 #[derive(Debug, Default)]
@@ -81,28 +149,123 @@ impl Algorithm for Random {
         self.queue.remove(i);
         Some(tile)
     }
+
+    // Any of them could come out next, so they're just listed in the order they went in
+    fn frontier(&self) -> Vec<(PathfinderTile, Option<f32>)> {
+        self.queue.iter().map(|&tile| (tile, None)).collect()
+    }
+}
+
+/**
+ * Pop everything off a copy of a heap, giving the exact order ties come out in rather than just a sorted one
+ */
+fn drain_heap<T: Ord>(mut heap: BinaryHeap<T>) -> impl Iterator<Item = T> {
+    std::iter::from_fn(move || heap.pop())
 }
 
 pub trait Algorithm {
     fn insert(&mut self, tile: PathfinderTile);
     fn next(&mut self) -> Option<PathfinderTile>;
+
+    /**
+     * Every pending tile in the order it would come out, along with the priority it's ordered by if there is one
+     */
+    fn frontier(&self) -> Vec<(PathfinderTile, Option<f32>)>;
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum AlgorithmOption {
     #[default]
     BreadthFirst,
+    Dijkstra,
     AStar,
     ReverseAStar,
     DepthFirst,
     Random,
+    ThetaStar,
+    LazyThetaStar,
+}
+
+impl AlgorithmOption {
+    pub const ALL: [AlgorithmOption; 8] = [
+        AlgorithmOption::BreadthFirst,
+        AlgorithmOption::Dijkstra,
+        AlgorithmOption::AStar,
+        AlgorithmOption::ReverseAStar,
+        AlgorithmOption::DepthFirst,
+        AlgorithmOption::Random,
+        AlgorithmOption::ThetaStar,
+        AlgorithmOption::LazyThetaStar,
+    ];
+
+    /**
+     * Short machine friendly name, used on the command line and in exported files
+     */
+    pub fn name(self) -> &'static str {
+        match self {
+            AlgorithmOption::BreadthFirst => "breadth-first",
+            AlgorithmOption::Dijkstra => "dijkstra",
+            AlgorithmOption::AStar => "a-star",
+            AlgorithmOption::ReverseAStar => "reverse-a-star",
+            AlgorithmOption::DepthFirst => "backtracking",
+            AlgorithmOption::Random => "random",
+            AlgorithmOption::ThetaStar => "theta-star",
+            AlgorithmOption::LazyThetaStar => "lazy-theta-star",
+        }
+    }
+
+    /**
+     * Whether a tile's parent can be any tile in sight rather than just a neighbor
+     */
+    pub fn any_angle(self) -> bool {
+        matches!(
+            self,
+            AlgorithmOption::ThetaStar | AlgorithmOption::LazyThetaStar
+        )
+    }
+
+    /**
+     * Whether tiles come out cheapest first, so a tile still waiting can be requeued when a cheaper way to it turns up
+     */
+    pub fn cost_ordered(self) -> bool {
+        matches!(
+            self,
+            AlgorithmOption::Dijkstra
+                | AlgorithmOption::AStar
+                | AlgorithmOption::ThetaStar
+                | AlgorithmOption::LazyThetaStar
+        )
+    }
+
+    /**
+     * What holds the frontier, and the order tiles come out of it
+     */
+    pub fn data_structure(self) -> &'static str {
+        match self {
+            AlgorithmOption::BreadthFirst => "Queue, first in first out",
+            AlgorithmOption::DepthFirst => "Stack, last in first out",
+            AlgorithmOption::Random => "List, picked at random",
+            AlgorithmOption::Dijkstra => "Binary heap, lowest cost so far first",
+            AlgorithmOption::AStar
+            | AlgorithmOption::ThetaStar
+            | AlgorithmOption::LazyThetaStar => "Binary heap, lowest priority first",
+            AlgorithmOption::ReverseAStar => "Binary heap, highest priority first",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|option| option.name() == name)
+    }
 }
 
 impl From<AlgorithmOption> for Box<dyn Algorithm + Send + Sync> {
     fn from(value: AlgorithmOption) -> Self {
         match value {
             AlgorithmOption::BreadthFirst => Box::new(BreadthFirst::default()),
-            AlgorithmOption::AStar => Box::new(AStar::default()),
+            AlgorithmOption::Dijkstra => Box::new(Dijkstra::default()),
+            AlgorithmOption::AStar
+            | AlgorithmOption::ThetaStar
+            | AlgorithmOption::LazyThetaStar => Box::new(AStar::default()),
             AlgorithmOption::ReverseAStar => Box::new(ReverseAStar::default()),
             AlgorithmOption::DepthFirst => Box::new(DepthFirst::default()),
             AlgorithmOption::Random => Box::new(Random::default()),
@@ -0,0 +1,372 @@
+use std::collections::HashSet;
+
+use bevy::prelude::*;
+use bevy_ecs_tilemap::prelude::*;
+
+use crate::{
+    ActiveFloor, MAP_SIZE, TileState, TileTerrain,
+    pathfinder_tile::{Cardinal, KeyColor},
+    smoothing::{line, tile_distance},
+    tile_center, tile_color,
+};
+
+pub fn brush_plugin(app: &mut App) {
+    app.init_resource::<Brush>()
+        .add_systems(Update, draw_brush_preview);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BrushTool {
+    // Paints while dragging, joining up the tiles between frames
+    #[default]
+    Freehand,
+    // The shapes are dragged out from where the button went down and painted when it comes up
+    Line,
+    Rectangle,
+    FilledRectangle,
+    Circle,
+    FilledCircle,
+    // Repaints the connected area of the same kind of tile that was clicked
+    Fill,
+}
+
+/**
+ * Every tile the brush can paint, portals are left to the keyboard since they're placed in pairs
+ */
+pub const PAINTS: [(TileState, &str); 15] = [
+    (TileState::Wall, "Wall"),
+    (TileState::Empty, "Empty"),
+    (TileState::Start, "Start"),
+    (TileState::Goal, "Goal"),
+    (TileState::Stairs, "Stairs"),
+    (TileState::OneWay(Cardinal::North), "One Way N"),
+    (TileState::OneWay(Cardinal::East), "One Way E"),
+    (TileState::OneWay(Cardinal::South), "One Way S"),
+    (TileState::OneWay(Cardinal::West), "One Way W"),
+    (TileState::Key(KeyColor::Yellow), "Yellow Key"),
+    (TileState::Key(KeyColor::Cyan), "Cyan Key"),
+    (TileState::Key(KeyColor::Magenta), "Magenta Key"),
+    (TileState::Door(KeyColor::Yellow), "Yellow Door"),
+    (TileState::Door(KeyColor::Cyan), "Cyan Door"),
+    (TileState::Door(KeyColor::Magenta), "Magenta Door"),
+];
+
+/**
+ * How the mouse paints tiles, the left button paints the chosen tile and the right button erases, except that
+ * pressing on a start or goal picks it up and drags it around instead
+ */
+#[derive(Debug, Resource)]
+pub struct Brush {
+    pub tool: BrushTool,
+    pub paint: TileState,
+    // Width in tiles of the square stamped along lines and outlines
+    pub size: u32,
+
+    // The button held down, where it went down, and the last tile it was over
+    stroke: Option<(MouseButton, TilePos, TilePos)>,
+    // The start or goal being dragged and where it is now
+    dragging: Option<(TileState, TilePos)>,
+}
+
+impl Default for Brush {
+    fn default() -> Self {
+        Self {
+            tool: BrushTool::default(),
+            paint: TileState::Wall,
+            size: 1,
+
+            stroke: None,
+            dragging: None,
+        }
+    }
+}
+
+impl Brush {
+    /**
+     * Feed in this frame's mouse buttons and the tile under the cursor, returning which tiles to set to what
+     */
+    pub fn update(
+        &mut self,
+        mouse: &ButtonInput<MouseButton>,
+        tile: Option<TilePos>,
+        state_at: impl Fn(TilePos) -> TileState,
+    ) -> Vec<(TilePos, TileState)> {
+        if let Some(moves) = self.drag(mouse, tile, &state_at) {
+            return moves;
+        }
+
+        match self.stroke(mouse, tile, state_at) {
+            Some((tiles, paint)) => tiles.into_iter().map(|pos| (pos, paint)).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /**
+     * Move a start or goal along with the cursor, leaving empty ground behind, returns None when not dragging
+     */
+    fn drag(
+        &mut self,
+        mouse: &ButtonInput<MouseButton>,
+        tile: Option<TilePos>,
+        state_at: &impl Fn(TilePos) -> TileState,
+    ) -> Option<Vec<(TilePos, TileState)>> {
+        let Some((endpoint, from)) = self.dragging else {
+            let tile = tile?;
+            let endpoint = state_at(tile);
+            if self.stroke.is_some()
+                || !mouse.just_pressed(MouseButton::Left)
+                || !matches!(endpoint, TileState::Start | TileState::Goal)
+            {
+                return None;
+            }
+
+            self.dragging = Some((endpoint, tile));
+            return Some(Vec::new());
+        };
+
+        if !mouse.pressed(MouseButton::Left) {
+            self.dragging = None;
+            return Some(Vec::new());
+        }
+
+        // Only onto open ground, so dragging can't wipe out walls or other endpoints
+        let to = tile.filter(|&to| {
+            to != from
+                && matches!(
+                    state_at(to),
+                    TileState::Empty
+                        | TileState::Queued(_)
+                        | TileState::Visited(_)
+                        | TileState::Final(_)
+                )
+        });
+        let Some(to) = to else {
+            return Some(Vec::new());
+        };
+
+        self.dragging = Some((endpoint, to));
+        Some(vec![(from, TileState::Empty), (to, endpoint)])
+    }
+
+    fn stroke(
+        &mut self,
+        mouse: &ButtonInput<MouseButton>,
+        tile: Option<TilePos>,
+        state_at: impl Fn(TilePos) -> TileState,
+    ) -> Option<(Vec<TilePos>, TileState)> {
+        let Some((button, anchor, last)) = self.stroke else {
+            let tile = tile?;
+            let button = [MouseButton::Left, MouseButton::Right]
+                .into_iter()
+                .find(|&button| mouse.just_pressed(button))?;
+            self.stroke = Some((button, tile, tile));
+
+            let paint = self.paint_for(button);
+            return match self.tool {
+                BrushTool::Freehand => Some((self.stamp([tile]), paint)),
+                BrushTool::Fill => {
+                    self.stroke = None;
+                    Some((flood_fill(tile, paint, state_at), paint))
+                }
+                _ => None,
+            };
+        };
+
+        let current = tile.unwrap_or(last);
+        self.stroke = Some((button, anchor, current));
+        let paint = self.paint_for(button);
+
+        if self.tool == BrushTool::Freehand {
+            if !mouse.pressed(button) {
+                self.stroke = None;
+            }
+
+            // Fast drags skip tiles between frames, so fill them in with a line
+            return (current != last).then(|| (self.stamp(line(last, current)), paint));
+        }
+
+        if mouse.pressed(button) {
+            return None;
+        }
+        self.stroke = None;
+        Some((self.stamp(self.shape(anchor, current)), paint))
+    }
+
+    /**
+     * Whether a button is down mid stroke, edits made until it comes up belong together
+     */
+    pub fn painting(&self) -> bool {
+        self.stroke.is_some() || self.dragging.is_some()
+    }
+
+    /**
+     * Tiles the shape being dragged out would paint if the button came up now
+     */
+    pub fn preview(&self) -> Option<(Vec<TilePos>, TileState)> {
+        let (button, anchor, last) = self.stroke?;
+        (self.tool != BrushTool::Freehand)
+            .then(|| (self.stamp(self.shape(anchor, last)), self.paint_for(button)))
+    }
+
+    fn paint_for(&self, button: MouseButton) -> TileState {
+        if button == MouseButton::Left {
+            self.paint
+        } else {
+            TileState::Empty
+        }
+    }
+
+    fn shape(&self, a: TilePos, b: TilePos) -> Vec<TilePos> {
+        match self.tool {
+            BrushTool::Freehand | BrushTool::Fill => vec![b],
+            BrushTool::Line => std::iter::once(a).chain(line(a, b)).collect(),
+            BrushTool::Rectangle => rectangle(a, b, false),
+            BrushTool::FilledRectangle => rectangle(a, b, true),
+            BrushTool::Circle => circle(a, b, false),
+            BrushTool::FilledCircle => circle(a, b, true),
+        }
+    }
+
+    /**
+     * Widen every tile into a square of the brush size, clipped to the map
+     */
+    fn stamp(&self, tiles: impl IntoIterator<Item = TilePos>) -> Vec<TilePos> {
+        let size = self.size.max(1) as i32;
+        let (low, high) = (-(size - 1) / 2, size / 2);
+
+        let mut stamped = HashSet::new();
+        for pos in tiles {
+            for dy in low..=high {
+                for dx in low..=high {
+                    let (x, y) = (pos.x as i32 + dx, pos.y as i32 + dy);
+                    if (0..MAP_SIZE as i32).contains(&x) && (0..MAP_SIZE as i32).contains(&y) {
+                        stamped.insert(TilePos::new(x as u32, y as u32));
+                    }
+                }
+            }
+        }
+
+        stamped.into_iter().collect()
+    }
+}
+
+fn rectangle(a: TilePos, b: TilePos, filled: bool) -> Vec<TilePos> {
+    let (min_x, max_x) = (a.x.min(b.x), a.x.max(b.x));
+    let (min_y, max_y) = (a.y.min(b.y), a.y.max(b.y));
+
+    (min_y..=max_y)
+        .flat_map(|y| (min_x..=max_x).map(move |x| TilePos::new(x, y)))
+        .filter(|pos| {
+            filled || pos.x == min_x || pos.x == max_x || pos.y == min_y || pos.y == max_y
+        })
+        .collect()
+}
+
+/**
+ * Circle centered on a passing through b, the outline is the ring of tiles within half a tile of the radius
+ */
+fn circle(a: TilePos, b: TilePos, filled: bool) -> Vec<TilePos> {
+    let radius = tile_distance(a, b);
+
+    (0..MAP_SIZE)
+        .flat_map(|y| (0..MAP_SIZE).map(move |x| TilePos::new(x, y)))
+        .filter(|&pos| {
+            let distance = tile_distance(a, pos);
+            distance <= radius + 0.5 && (filled || distance > radius - 0.5)
+        })
+        .collect()
+}
+
+/**
+ * The 4-connected area around a tile of the same state, with search progress counting as empty ground
+ */
+fn flood_fill(
+    from: TilePos,
+    paint: TileState,
+    state_at: impl Fn(TilePos) -> TileState,
+) -> Vec<TilePos> {
+    let plain = |state: TileState| match state {
+        TileState::Queued(_) | TileState::Visited(_) | TileState::Final(_) => TileState::Empty,
+        state => state,
+    };
+    let target = plain(state_at(from));
+    if target == paint {
+        return Vec::new();
+    }
+
+    let mut seen = HashSet::from([from]);
+    let mut stack = vec![from];
+    while let Some(pos) = stack.pop() {
+        for direction in Cardinal::ALL {
+            let (dx, dy) = direction.offset();
+            let (x, y) = (pos.x as i32 + dx, pos.y as i32 + dy);
+            if !(0..MAP_SIZE as i32).contains(&x) || !(0..MAP_SIZE as i32).contains(&y) {
+                continue;
+            }
+
+            let next = TilePos::new(x as u32, y as u32);
+            if plain(state_at(next)) == target && seen.insert(next) {
+                stack.push(next);
+            }
+        }
+    }
+
+    seen.into_iter().collect()
+}
+
+/**
+ * System that outlines the tiles a shape would paint while it's being dragged out
+ */
+fn draw_brush_preview(
+    mut gizmos: Gizmos,
+    brush: Res<Brush>,
+    tilemap: Single<
+        (
+            &TilemapSize,
+            &TilemapGridSize,
+            &TilemapTileSize,
+            &TilemapType,
+            &TilemapAnchor,
+            &Transform,
+        ),
+        With<ActiveFloor>,
+    >,
+) {
+    let Some((tiles, paint)) = brush.preview() else {
+        return;
+    };
+    let grid_size = tilemap.1;
+    let color = tile_color(paint, TileTerrain::Plains);
+
+    for pos in tiles {
+        gizmos.rect_2d(
+            tile_center(*tilemap, pos),
+            Vec2::new(grid_size.x, grid_size.y) * 0.8,
+            color,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flood_fill_stays_inside_walls() {
+        // A ring of walls around a 3x3 room, with some search progress left inside it
+        let state_at = |pos: TilePos| match (pos.x, pos.y) {
+            (0 | 4, 0..=4) | (0..=4, 0 | 4) => TileState::Wall,
+            (1, 1) => TileState::Visited(2),
+            _ => TileState::Empty,
+        };
+
+        let mut filled = flood_fill(TilePos::new(2, 2), TileState::Wall, state_at);
+        filled.sort_by_key(|pos| (pos.y, pos.x));
+
+        let room: Vec<TilePos> = (1..4)
+            .flat_map(|y| (1..4).map(move |x| TilePos::new(x, y)))
+            .collect();
+        assert_eq!(filled, room);
+        assert!(flood_fill(TilePos::new(0, 0), TileState::Wall, state_at).is_empty());
+    }
+}
@@ -0,0 +1,241 @@
+use bevy::prelude::*;
+use bevy_ecs_tilemap::tiles::{TilePos, TileStorage};
+use image::{ImageResult, Rgba, RgbaImage};
+
+use crate::{
+    MAP_SIZE, TileState, TileTerrain,
+    pathfinder_tile::{Cardinal, KeyColor},
+    tile_color,
+};
+
+// Portal pairs past this wrap back around to the same hue, so they can't be told apart in an image
+const PORTAL_COLORS: u32 = 360;
+
+/**
+ * Which parts of the search show up in an image, anything left out is drawn as the map underneath
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExportOverlays {
+    pub visited: bool,
+    pub queued: bool,
+    pub final_path: bool,
+}
+
+impl ExportOverlays {
+    pub const ALL: Self = Self {
+        visited: true,
+        queued: true,
+        final_path: true,
+    };
+
+    fn shows(self, state: TileState) -> bool {
+        match state {
+            TileState::Visited(_) => self.visited,
+            TileState::Queued(_) => self.queued,
+            TileState::Final(_) => self.final_path,
+            _ => true,
+        }
+    }
+}
+
+impl Default for ExportOverlays {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+/**
+ * Draw the map into an image, one square of pixels per tile, with whichever parts of the search progress are asked for
+ */
+pub fn render_map(
+    tile_states: Query<&TileState>,
+    tile_terrains: Query<&TileTerrain>,
+    storage: &TileStorage,
+    pixels_per_tile: u32,
+    overlays: ExportOverlays,
+) -> RgbaImage {
+    let mut image = RgbaImage::new(MAP_SIZE * pixels_per_tile, MAP_SIZE * pixels_per_tile);
+
+    for x in 0..MAP_SIZE {
+        for y in 0..MAP_SIZE {
+            let entity = storage.checked_get(&TilePos::new(x, y)).unwrap();
+            let mut state = *tile_states.get(entity).unwrap();
+            let terrain = *tile_terrains.get(entity).unwrap();
+
+            if !overlays.shows(state) {
+                state = TileState::Empty;
+            }
+
+            let pixel = rgba(tile_color(state, terrain));
+
+            // Images count rows from the top, but TilePos counts up from the bottom
+            let top = (MAP_SIZE - 1 - y) * pixels_per_tile;
+            for px in 0..pixels_per_tile {
+                for py in 0..pixels_per_tile {
+                    let pixel = match state {
+                        TileState::OneWay(direction)
+                            if on_exit_edge(direction, px, py, pixels_per_tile) =>
+                        {
+                            exit_color(state)
+                        }
+                        _ => pixel,
+                    };
+                    image.put_pixel(x * pixels_per_tile + px, top + py, pixel);
+                }
+            }
+        }
+    }
+
+    image
+}
+
+/**
+ * Save the map as a PNG, see render_map
+ */
+pub fn export_png(
+    tile_states: Query<&TileState>,
+    tile_terrains: Query<&TileTerrain>,
+    storage: &TileStorage,
+    path: &str,
+    pixels_per_tile: u32,
+    overlays: ExportOverlays,
+) -> ImageResult<()> {
+    render_map(
+        tile_states,
+        tile_terrains,
+        storage,
+        pixels_per_tile,
+        overlays,
+    )
+    .save(path)
+}
+
+/**
+ * Load a map from an image of any size, matching the center pixel of each tile to the closest palette color
+ *
+ * Features are drawn over their terrain, so only empty tiles get their terrain back, the rest come in as plains.
+ * One way tiles are read from their darkened exit edge, which needs at least 3 pixels per tile.
+ */
+pub fn import_png(
+    mut tile_states: Query<&mut TileState>,
+    mut tile_terrains: Query<&mut TileTerrain>,
+    storage: &TileStorage,
+    path: &str,
+) -> ImageResult<()> {
+    let image = image::open(path)?.to_rgba8();
+    let palette = palette();
+    let mut lost_directions = 0;
+
+    for x in 0..MAP_SIZE {
+        for y in 0..MAP_SIZE {
+            // Span of image pixels covering this tile, rows flipped since images count from the top
+            let row = MAP_SIZE - 1 - y;
+            let left = x * image.width() / MAP_SIZE;
+            let top = row * image.height() / MAP_SIZE;
+            let (right, bottom) = (
+                ((x + 1) * image.width() / MAP_SIZE).max(left + 1) - 1,
+                ((row + 1) * image.height() / MAP_SIZE).max(top + 1) - 1,
+            );
+            let (center_x, center_y) = ((left + right).div_ceil(2), (top + bottom).div_ceil(2));
+            let pixel = *image.get_pixel(center_x, center_y);
+
+            let &(mut state, terrain, _) = palette
+                .iter()
+                .min_by_key(|(_, _, color)| color_distance(pixel, *color))
+                .unwrap();
+
+            if let TileState::OneWay(_) = state {
+                if right - left < 2 || bottom - top < 2 {
+                    lost_directions += 1;
+                } else {
+                    let exit = exit_color(state);
+                    let direction = [
+                        (Cardinal::North, (center_x, top)),
+                        (Cardinal::East, (right, center_y)),
+                        (Cardinal::South, (center_x, bottom)),
+                        (Cardinal::West, (left, center_y)),
+                    ]
+                    .into_iter()
+                    .min_by_key(|&(_, (px, py))| color_distance(*image.get_pixel(px, py), exit))
+                    .unwrap()
+                    .0;
+                    state = TileState::OneWay(direction);
+                }
+            }
+
+            let entity = storage.checked_get(&TilePos::new(x, y)).unwrap();
+            *tile_states.get_mut(entity).unwrap() = state;
+            *tile_terrains.get_mut(entity).unwrap() = terrain;
+        }
+    }
+
+    if lost_directions > 0 {
+        warn!(
+            "image too small to read the direction of {} one way tiles, they all point {:?}",
+            lost_directions,
+            Cardinal::East
+        );
+    }
+
+    Ok(())
+}
+
+/**
+ * Every tile that can be drawn by hand and the color it's exported as, search progress isn't worth importing
+ */
+fn palette() -> Vec<(TileState, TileTerrain, Rgba<u8>)> {
+    [
+        TileTerrain::Plains,
+        TileTerrain::Forest,
+        TileTerrain::Mountains,
+    ]
+    .map(|terrain| (TileState::Empty, terrain))
+    .into_iter()
+    .chain(
+        [
+            TileState::Wall,
+            TileState::Start,
+            TileState::Goal,
+            TileState::Stairs,
+            // The direction is filled in from the exit edge afterwards
+            TileState::OneWay(Cardinal::East),
+        ]
+        .into_iter()
+        .chain(
+            KeyColor::ALL
+                .into_iter()
+                .flat_map(|color| [TileState::Key(color), TileState::Door(color)]),
+        )
+        .chain((0..PORTAL_COLORS).map(TileState::Portal))
+        .map(|state| (state, TileTerrain::Plains)),
+    )
+    .map(|(state, terrain)| (state, terrain, rgba(tile_color(state, terrain))))
+    .collect()
+}
+
+fn rgba(color: Color) -> Rgba<u8> {
+    Rgba(color.to_srgba().to_u8_array())
+}
+
+fn color_distance(Rgba(a): Rgba<u8>, Rgba(b): Rgba<u8>) -> i32 {
+    (0..3).map(|i| (a[i] as i32 - b[i] as i32).pow(2)).sum()
+}
+
+/**
+ * One way tiles mark the side they can be left through with a darker edge, so the direction survives a round trip
+ */
+fn exit_color(state: TileState) -> Rgba<u8> {
+    rgba(tile_color(state, TileTerrain::Plains).mix(&Color::BLACK, 0.5))
+}
+
+// Tiles too small to keep a center pixel clear of the edge don't get one
+fn on_exit_edge(direction: Cardinal, px: u32, py: u32, pixels_per_tile: u32) -> bool {
+    let last = pixels_per_tile - 1;
+    pixels_per_tile >= 3
+        && match direction {
+            Cardinal::North => py == 0,
+            Cardinal::East => px == last,
+            Cardinal::South => py == last,
+            Cardinal::West => px == 0,
+        }
+}
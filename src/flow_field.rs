@@ -0,0 +1,286 @@
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+use bevy::{color::palettes::basic, prelude::*};
+use bevy_ecs_tilemap::prelude::*;
+
+use crate::{
+    ActiveFloor, CurrentFloor, Floor, MAP_SIZE, TileState, TileTerrain, color_tile,
+    pathfinder::Pathfinder,
+    pathfinder_tile::{Topology, offset},
+    smoothing::blocks_sight,
+    tile_center, tile_color,
+};
+
+pub fn flow_field_plugin(app: &mut App) {
+    app.init_resource::<FlowField>().add_systems(
+        Update,
+        (update_flow_field.after(color_tile), draw_flow_arrows).chain(),
+    );
+}
+
+/**
+ * Cost to reach the nearest goal from every tile of the current floor, and which way to go from each one,
+ * so any number of units heading to the same place can share one search
+ */
+#[derive(Debug, Resource)]
+pub struct FlowField {
+    pub enabled: bool,
+    pub heatmap: bool,
+    pub arrows: bool,
+
+    // Indexed by y * MAP_SIZE + x, None where no goal can be reached
+    integration: Vec<Option<u32>>,
+    directions: Vec<Option<Vec2>>,
+    pub max_cost: u32,
+    // Topology the field was last worked out with, None when it needs working out again
+    topology: Option<Topology>,
+
+    // Whether tile colors currently show the heatmap and need putting back
+    painted: bool,
+}
+
+impl Default for FlowField {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            heatmap: true,
+            arrows: true,
+
+            integration: Vec::new(),
+            directions: Vec::new(),
+            max_cost: 0,
+            topology: None,
+
+            painted: false,
+        }
+    }
+}
+
+impl FlowField {
+    /**
+     * Dijkstra outwards from every goal at once, stepping onto a tile costs its terrain's cost
+     */
+    fn integrate(
+        &mut self,
+        topology: Topology,
+        cell: impl Fn(TilePos) -> (TileState, TileTerrain),
+    ) {
+        let index = |pos: TilePos| (pos.y * MAP_SIZE + pos.x) as usize;
+        let size = (MAP_SIZE * MAP_SIZE) as usize;
+        self.integration = vec![None; size];
+        self.directions = vec![None; size];
+        self.max_cost = 0;
+
+        let mut queue = BinaryHeap::new();
+        for y in 0..MAP_SIZE {
+            for x in 0..MAP_SIZE {
+                if cell(TilePos::new(x, y)).0 == TileState::Goal {
+                    self.integration[index(TilePos::new(x, y))] = Some(0);
+                    queue.push(Reverse((0, x, y)));
+                }
+            }
+        }
+
+        while let Some(Reverse((cost, x, y))) = queue.pop() {
+            let pos = TilePos::new(x, y);
+            if self.integration[index(pos)].is_some_and(|best| best < cost) {
+                continue;
+            }
+            self.max_cost = self.max_cost.max(cost);
+
+            // Walking the other way, from the neighbor onto this tile
+            let (_, terrain) = cell(pos);
+            for (_, _, neighbor) in neighbors(pos, topology, false) {
+                let (state, _) = cell(neighbor);
+                if blocks_sight(state) {
+                    continue;
+                }
+
+                let next = cost + terrain.cost();
+                if self.integration[index(neighbor)].is_none_or(|best| next < best) {
+                    self.integration[index(neighbor)] = Some(next);
+                    queue.push(Reverse((next, neighbor.x, neighbor.y)));
+                }
+            }
+        }
+
+        // Every tile points at its cheapest neighbor, diagonals only when both sides around the corner are open
+        for y in 0..MAP_SIZE {
+            for x in 0..MAP_SIZE {
+                let pos = TilePos::new(x, y);
+                let Some(cost) = self.integration[index(pos)] else {
+                    continue;
+                };
+
+                let best = neighbors(pos, topology, true)
+                    .filter_map(|(dx, dy, neighbor)| {
+                        if dx != 0 && dy != 0 {
+                            let side_a = offset_pos(pos, dx, 0, topology)?;
+                            let side_b = offset_pos(pos, 0, dy, topology)?;
+                            self.integration[index(side_a)]?;
+                            self.integration[index(side_b)]?;
+                        }
+
+                        Some((self.integration[index(neighbor)]?, dx, dy))
+                    })
+                    .min_by_key(|&(cost, dx, dy)| (cost, dx.abs() + dy.abs()));
+
+                if let Some((next, dx, dy)) = best
+                    && next < cost
+                {
+                    self.directions[index(pos)] = Some(Vec2::new(dx as f32, dy as f32).normalize());
+                }
+            }
+        }
+    }
+
+    pub fn cost(&self, pos: TilePos) -> Option<u32> {
+        *self.integration.get((pos.y * MAP_SIZE + pos.x) as usize)?
+    }
+
+    pub fn direction(&self, pos: TilePos) -> Option<Vec2> {
+        *self.directions.get((pos.y * MAP_SIZE + pos.x) as usize)?
+    }
+}
+
+fn offset_pos(pos: TilePos, dx: i32, dy: i32, topology: Topology) -> Option<TilePos> {
+    Some(TilePos::new(
+        offset(pos.x, dx, MAP_SIZE, topology.wraps_x())?,
+        offset(pos.y, dy, MAP_SIZE, topology.wraps_y())?,
+    ))
+}
+
+/**
+ * The four, or with diagonals eight, tiles around a position along with the step to each,
+ * following the pathfinder's topology
+ */
+fn neighbors(
+    pos: TilePos,
+    topology: Topology,
+    diagonals: bool,
+) -> impl Iterator<Item = (i32, i32, TilePos)> {
+    [
+        (1, 0),
+        (-1, 0),
+        (0, 1),
+        (0, -1),
+        (1, 1),
+        (1, -1),
+        (-1, 1),
+        (-1, -1),
+    ]
+    .into_iter()
+    .take(if diagonals { 8 } else { 4 })
+    .filter_map(move |(dx, dy)| Some((dx, dy, offset_pos(pos, dx, dy, topology)?)))
+}
+
+/**
+ * Blue next to a goal, shading to red at the farthest reachable tile
+ */
+fn heat_color(cost: u32, max_cost: u32) -> Color {
+    let ratio = cost as f32 / max_cost.max(1) as f32;
+    Color::hsl(240.0 * (1.0 - ratio), 0.8, 0.5)
+}
+
+/**
+ * System that works the field out again whenever the current floor's tiles, the floor or the topology change, and
+ * paints the heatmap over open tiles
+ * Tiles get their usual colors back when the heatmap is turned off
+ */
+fn update_flow_field(
+    mut field: ResMut<FlowField>,
+    pathfinder: Res<Pathfinder>,
+    current: Res<CurrentFloor>,
+    changed: Query<&Floor, Or<(Changed<TileState>, Changed<TileTerrain>)>>,
+    mut tiles: Query<(&TileState, &TileTerrain, &mut TileColor)>,
+    storage: Single<&TileStorage, With<ActiveFloor>>,
+) {
+    let show = field.enabled && field.heatmap;
+
+    if field.painted && !show {
+        for (&state, &terrain, mut color) in tiles.iter_mut() {
+            color.0 = tile_color(state, terrain);
+        }
+        field.painted = false;
+    }
+
+    if !field.enabled {
+        field.integration.clear();
+        field.directions.clear();
+        field.topology = None;
+        return;
+    }
+
+    let stale = field.topology != Some(pathfinder.topology)
+        || current.is_changed()
+        || changed.iter().any(|&Floor(floor)| floor == current.0);
+    if stale {
+        let cell = |pos: TilePos| {
+            let (&state, &terrain, _) = tiles.get(storage.checked_get(&pos).unwrap()).unwrap();
+            (state, terrain)
+        };
+        field.integrate(pathfinder.topology, cell);
+        field.topology = Some(pathfinder.topology);
+    }
+
+    if show {
+        for y in 0..MAP_SIZE {
+            for x in 0..MAP_SIZE {
+                let pos = TilePos::new(x, y);
+                let (&state, &terrain, mut color) =
+                    tiles.get_mut(storage.checked_get(&pos).unwrap()).unwrap();
+                if state != TileState::Empty {
+                    continue;
+                }
+
+                // Tiles no goal can reach go back to their usual color, in case they were heat colored before
+                let wanted = match field.cost(pos) {
+                    Some(cost) => heat_color(cost, field.max_cost),
+                    None => tile_color(state, terrain),
+                };
+                // Put back every frame in case the tile was recolored, but only written when it differs
+                if color.0 != wanted {
+                    color.0 = wanted;
+                }
+            }
+        }
+        field.painted = true;
+    }
+}
+
+/**
+ * System that draws an arrow on every tile pointing the way to the nearest goal
+ */
+fn draw_flow_arrows(
+    mut gizmos: Gizmos,
+    field: Res<FlowField>,
+    tilemap: Single<
+        (
+            &TilemapSize,
+            &TilemapGridSize,
+            &TilemapTileSize,
+            &TilemapType,
+            &TilemapAnchor,
+            &Transform,
+        ),
+        With<ActiveFloor>,
+    >,
+) {
+    if !field.enabled || !field.arrows {
+        return;
+    }
+
+    let (_, grid_size, ..) = *tilemap;
+    for y in 0..MAP_SIZE {
+        for x in 0..MAP_SIZE {
+            let pos = TilePos::new(x, y);
+            let Some(direction) = field.direction(pos) else {
+                continue;
+            };
+
+            let center = tile_center(*tilemap, pos);
+            let reach = direction * grid_size.x * 0.4;
+            gizmos.arrow_2d(center - reach, center + reach, basic::BLACK);
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use bevy::{color::palettes::basic, prelude::*};
+use bevy_ecs_tilemap::prelude::*;
+use bevy_egui::{
+    EguiContexts, EguiPrimaryContextPass,
+    egui::{Color32, RichText, ScrollArea, TextStyle, Window},
+};
+
+use crate::{
+    ActiveFloor, CurrentFloor, algorithm::AlgorithmOption, pathfinder::Pathfinder, tile_center,
+};
+
+pub fn frontier_plugin(app: &mut App) {
+    app.init_resource::<FrontierViewer>()
+        .add_systems(Update, draw_next_tile)
+        .add_systems(EguiPrimaryContextPass, show_frontier);
+}
+
+/**
+ * Lists what's waiting in the algorithm's queue, stack or heap in the order it'll come out
+ */
+#[derive(Debug, Default, Resource)]
+pub struct FrontierViewer {
+    pub enabled: bool,
+}
+
+/**
+ * System that fills in the frontier window, marking the tile that comes out next and any tied with it
+ */
+fn show_frontier(
+    mut contexts: EguiContexts,
+    viewer: Res<FrontierViewer>,
+    pathfinder: Res<Pathfinder>,
+) {
+    if !viewer.enabled {
+        return;
+    }
+
+    let option = pathfinder.option();
+    let frontier = pathfinder.frontier();
+    // Nothing is next with random picks, any of them could be
+    let ordered = option != AlgorithmOption::Random;
+    let head = frontier.first().and_then(|&(_, priority)| priority);
+
+    Window::new("Frontier").show(contexts.ctx_mut().unwrap(), |ui| {
+        ui.label(option.data_structure());
+        ui.label(format!("{} tiles pending", frontier.len()));
+        ui.separator();
+
+        let row_height = ui.text_style_height(&TextStyle::Monospace);
+        ScrollArea::vertical().max_height(300.0).show_rows(
+            ui,
+            row_height,
+            frontier.len(),
+            |ui, rows| {
+                for index in rows {
+                    let (tile, priority) = frontier[index];
+                    let mut text = format!("{:>4}  {}", index, tile);
+                    if let Some(priority) = priority {
+                        text += &format!("  priority {:.2}", priority);
+                    }
+
+                    // Ties with the head could come out in either order, the heap just happens to pick one
+                    let tied = index > 0 && priority.is_some() && priority == head;
+                    if tied {
+                        text += "  (tie)";
+                    }
+
+                    let text = RichText::new(text).monospace();
+                    if ordered && index == 0 {
+                        ui.label(text.strong().color(Color32::LIGHT_GREEN));
+                    } else if ordered && tied {
+                        ui.label(text.color(Color32::YELLOW));
+                    } else {
+                        ui.label(text);
+                    }
+                }
+            },
+        );
+    });
+}
+
+/**
+ * System that circles the tile that will be stepped on next
+ */
+fn draw_next_tile(
+    mut gizmos: Gizmos,
+    viewer: Res<FrontierViewer>,
+    pathfinder: Res<Pathfinder>,
+    current: Res<CurrentFloor>,
+    tilemap: Single<
+        (
+            &TilemapSize,
+            &TilemapGridSize,
+            &TilemapTileSize,
+            &TilemapType,
+            &TilemapAnchor,
+            &Transform,
+        ),
+        With<ActiveFloor>,
+    >,
+) {
+    if !viewer.enabled || pathfinder.complete || pathfinder.option() == AlgorithmOption::Random {
+        return;
+    }
+
+    let Some(&(next, _)) = pathfinder.frontier().first() else {
+        return;
+    };
+    if next.floor != current.0 {
+        return;
+    }
+
+    let grid_size = tilemap.1;
+    gizmos.circle_2d(
+        tile_center(*tilemap, next.pos),
+        grid_size.x * 0.45,
+        basic::LIME,
+    );
+}
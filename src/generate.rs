@@ -1,11 +1,13 @@
+use std::{cmp::Reverse, collections::BinaryHeap};
+
 use bevy::prelude::*;
 use bevy_ecs_tilemap::tiles::{TilePos, TileStorage};
 use maze_generator::prelude::*;
 use maze_generator::recursive_backtracking::RbGenerator;
-use noise::{NoiseFn, Perlin};
-use rand::{Rng, rng};
+use noise::{Fbm, MultiFractal, NoiseFn, Perlin, RidgedMulti};
+use rand::{Rng, SeedableRng, rng, rngs::StdRng};
 
-use crate::{MAP_SIZE, TileParent, TileState};
+use crate::{MAP_SIZE, TileParent, TileState, TileTerrain};
 
 pub fn flush_path(mut tile_states: Query<&mut TileState>, mut parents: Query<&mut TileParent>) {
     for mut tile_state in tile_states.iter_mut() {
@@ -22,43 +24,154 @@ pub fn flush_path(mut tile_states: Query<&mut TileState>, mut parents: Query<&mu
     }
 }
 
-pub fn generate_flat(mut tile_states: Query<&mut TileState>, fill: TileState) {
-    for mut tile_state in tile_states.iter_mut() {
-        *tile_state = fill;
+/**
+ * Fill every tile of one floor, the other floors are left alone
+ */
+pub fn generate_flat(
+    mut tile_states: Query<&mut TileState>,
+    mut tile_terrains: Query<&mut TileTerrain>,
+    storage: &TileStorage,
+    fill: TileState,
+) {
+    for &entity in storage.iter().flatten() {
+        *tile_states.get_mut(entity).unwrap() = fill;
+        *tile_terrains.get_mut(entity).unwrap() = TileTerrain::Plains;
+    }
+}
+
+/**
+ * Every position on a floor, bottom row first
+ */
+fn positions() -> impl Iterator<Item = TilePos> {
+    (0..MAP_SIZE).flat_map(|y| (0..MAP_SIZE).map(move |x| TilePos::new(x, y)))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FractalOption {
+    #[default]
+    Single,
+    Fbm,
+    Ridged,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct NoiseSettings {
+    pub fractal: FractalOption,
+    pub scale: f64,
+
+    // Only used by the multi-octave fractals
+    pub octaves: usize,
+    pub lacunarity: f64,
+    pub persistence: f64,
+
+    // Noise values are sorted into bands, anything above the last threshold is a wall
+    pub forest_threshold: f64,
+    pub mountain_threshold: f64,
+    pub wall_threshold: f64,
+}
+
+impl Default for NoiseSettings {
+    fn default() -> Self {
+        Self {
+            fractal: FractalOption::default(),
+            scale: 5.5,
+
+            octaves: 4,
+            lacunarity: 2.0,
+            persistence: 0.5,
+
+            forest_threshold: -0.1,
+            mountain_threshold: 0.15,
+            wall_threshold: 0.35,
+        }
+    }
+}
+
+impl NoiseSettings {
+    /**
+     * Build the picked noise function with a fresh random seed
+     */
+    fn build(&self) -> Box<dyn NoiseFn<f64, 2>> {
+        let seed = rng().random();
+
+        match self.fractal {
+            FractalOption::Single => Box::new(Perlin::new(seed)),
+            FractalOption::Fbm => Box::new(
+                Fbm::<Perlin>::new(seed)
+                    .set_octaves(self.octaves)
+                    .set_lacunarity(self.lacunarity)
+                    .set_persistence(self.persistence),
+            ),
+            FractalOption::Ridged => Box::new(
+                RidgedMulti::<Perlin>::new(seed)
+                    .set_octaves(self.octaves)
+                    .set_lacunarity(self.lacunarity)
+                    .set_persistence(self.persistence),
+            ),
+        }
+    }
+
+    /**
+     * Sort a noise value into its band, lowest band is plain empty ground
+     */
+    fn classify(&self, value: f64) -> (TileState, TileTerrain) {
+        if value > self.wall_threshold {
+            (TileState::Wall, TileTerrain::Plains)
+        } else if value > self.mountain_threshold {
+            (TileState::Empty, TileTerrain::Mountains)
+        } else if value > self.forest_threshold {
+            (TileState::Empty, TileTerrain::Forest)
+        } else {
+            (TileState::Empty, TileTerrain::Plains)
+        }
     }
 }
 
 pub fn generate_noise(
     mut tile_states: Query<&mut TileState>,
-    mut tile_positions: Query<&TilePos>,
-    scale: f64,
-    threshold: f64,
+    mut tile_terrains: Query<&mut TileTerrain>,
+    storage: &TileStorage,
+    settings: &NoiseSettings,
 ) {
-    generate_flat(tile_states.reborrow(), TileState::Empty);
+    generate_flat(
+        tile_states.reborrow(),
+        tile_terrains.reborrow(),
+        storage,
+        TileState::Empty,
+    );
 
-    let noise = Perlin::new(rng().random());
+    let noise = settings.build();
 
-    let mut lens = tile_positions.join::<_, (&mut TileState, &TilePos)>(&mut tile_states);
+    for TilePos { x, y } in positions() {
+        let value = noise.get([
+            x as f64 / settings.scale + 0.5,
+            y as f64 / settings.scale + 0.5,
+        ]);
+        let (state, terrain) = settings.classify(value);
 
-    for (mut tile_state, &TilePos { x, y }) in lens.query().iter_mut() {
-        if noise.get([x as f64 / scale + 0.5, y as f64 / scale + 0.5]) > threshold {
-            *tile_state = TileState::Wall;
-        }
+        let entity = storage.checked_get(&TilePos::new(x, y)).unwrap();
+        *tile_states.get_mut(entity).unwrap() = state;
+        *tile_terrains.get_mut(entity).unwrap() = terrain;
     }
 }
 
 pub fn generate_maze(
     mut tile_states: Query<&mut TileState>,
-    tile_positions: Query<&TilePos>,
+    tile_terrains: Query<&mut TileTerrain>,
     storage: &TileStorage,
 ) {
-    generate_flat(tile_states.reborrow(), TileState::Empty);
+    generate_flat(
+        tile_states.reborrow(),
+        tile_terrains,
+        storage,
+        TileState::Empty,
+    );
 
     let maze = RbGenerator::new(None)
         .generate(MAP_SIZE as i32 / 2, MAP_SIZE as i32 / 2)
         .unwrap();
 
-    for &TilePos { x, y } in tile_positions {
+    for TilePos { x, y } in positions() {
         match (x % 2 == 0, y % 2 == 0) {
             (false, false) => {
                 let entity = storage.checked_get(&TilePos::new(x, y)).unwrap();
@@ -92,3 +205,338 @@ pub fn generate_maze(
         }
     }
 }
+
+// How many times wave function collapse reseeds after hitting a contradiction before giving up
+const WFC_MAX_ATTEMPTS: usize = 32;
+
+// Every sample cell kind gets a bit in a u64 possibility mask
+const WFC_MAX_KINDS: usize = 64;
+
+type WfcCell = (TileState, TileTerrain);
+
+/**
+ * Example grid wave function collapse learns adjacency from, rows are stored bottom to top like TilePos
+ */
+#[derive(Debug, Clone, Default)]
+pub struct WfcSample {
+    width: u32,
+    height: u32,
+    cells: Vec<WfcCell>,
+}
+
+impl WfcSample {
+    /**
+     * Capture the bottom left corner of the map as an example, so the user can paint one directly
+     */
+    pub fn capture(
+        tile_states: Query<&mut TileState>,
+        tile_terrains: Query<&mut TileTerrain>,
+        storage: &TileStorage,
+        size: u32,
+    ) -> Self {
+        let size = size.min(MAP_SIZE);
+        let mut cells = Vec::with_capacity((size * size) as usize);
+
+        for y in 0..size {
+            for x in 0..size {
+                let entity = storage.checked_get(&TilePos::new(x, y)).unwrap();
+                cells.push(wfc_cell(
+                    *tile_states.get(entity).unwrap(),
+                    *tile_terrains.get(entity).unwrap(),
+                ));
+            }
+        }
+
+        Self {
+            width: size,
+            height: size,
+            cells,
+        }
+    }
+
+    /**
+     * Load an example from a text file, one character per tile: `.` plains, `f` forest, `m` mountains, `#` wall
+     */
+    pub fn load(path: &str) -> std::io::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        let lines: Vec<&str> = text
+            .lines()
+            .map(str::trim_end)
+            .filter(|line| !line.is_empty())
+            .collect();
+
+        let width = lines.first().map_or(0, |line| line.chars().count());
+        if width == 0 || lines.iter().any(|line| line.chars().count() != width) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "sample must be a non-empty rectangle",
+            ));
+        }
+
+        // Files are written top to bottom, but TilePos counts up from the bottom
+        let mut cells = Vec::with_capacity(width * lines.len());
+        for line in lines.iter().rev() {
+            for tile in line.chars() {
+                cells.push(match tile {
+                    '.' => (TileState::Empty, TileTerrain::Plains),
+                    'f' => (TileState::Empty, TileTerrain::Forest),
+                    'm' => (TileState::Empty, TileTerrain::Mountains),
+                    '#' => (TileState::Wall, TileTerrain::Plains),
+                    other => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("unknown sample tile {other:?}"),
+                        ));
+                    }
+                });
+            }
+        }
+
+        Ok(Self {
+            width: width as u32,
+            height: lines.len() as u32,
+            cells,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+}
+
+/**
+ * Strip anything that shouldn't be copied around the map, like search progress and endpoints
+ */
+fn wfc_cell(state: TileState, terrain: TileTerrain) -> WfcCell {
+    match state {
+        TileState::Wall => (TileState::Wall, terrain),
+        _ => (TileState::Empty, terrain),
+    }
+}
+
+/**
+ * Adjacency rules learned from a sample, indexed by cell kind
+ */
+struct WfcRules {
+    kinds: Vec<WfcCell>,
+    weights: Vec<u32>,
+    // allowed[direction][kind] is a mask of kinds that may sit in that direction of kind
+    allowed: [Vec<u64>; 4],
+}
+
+// Right, left, up, down, with each direction's opposite at index ^ 1
+const WFC_DIRECTIONS: [(i32, i32); 4] = [(1, 0), (-1, 0), (0, 1), (0, -1)];
+
+impl WfcRules {
+    fn learn(sample: &WfcSample) -> Option<Self> {
+        let mut kinds = Vec::new();
+        let mut weights = Vec::new();
+        let mut indices = Vec::with_capacity(sample.cells.len());
+
+        for &cell in &sample.cells {
+            let index = match kinds.iter().position(|&kind| kind == cell) {
+                Some(index) => index,
+                None => {
+                    kinds.push(cell);
+                    weights.push(0);
+                    kinds.len() - 1
+                }
+            };
+            weights[index] += 1;
+            indices.push(index);
+        }
+
+        if kinds.is_empty() || kinds.len() > WFC_MAX_KINDS {
+            return None;
+        }
+
+        let mut allowed: [Vec<u64>; 4] = Default::default();
+        for (direction, &(dx, dy)) in WFC_DIRECTIONS.iter().enumerate() {
+            allowed[direction] = vec![0; kinds.len()];
+
+            for y in 0..sample.height {
+                for x in 0..sample.width {
+                    let (nx, ny) = (x as i32 + dx, y as i32 + dy);
+                    if nx < 0 || ny < 0 || nx >= sample.width as i32 || ny >= sample.height as i32 {
+                        continue;
+                    }
+
+                    let kind = indices[(y * sample.width + x) as usize];
+                    let neighbor = indices[(ny as u32 * sample.width + nx as u32) as usize];
+                    allowed[direction][kind] |= 1 << neighbor;
+                }
+            }
+
+            // Kinds only ever seen on the sample's edge have no rules that way, so don't restrict them
+            for mask in allowed[direction].iter_mut() {
+                if *mask == 0 {
+                    *mask = u64::MAX >> (WFC_MAX_KINDS - kinds.len());
+                }
+            }
+        }
+
+        Some(Self {
+            kinds,
+            weights,
+            allowed,
+        })
+    }
+
+    /**
+     * Run one attempt of wave function collapse, returning None on a contradiction
+     */
+    fn collapse(&self, width: u32, height: u32, rng: &mut impl Rng) -> Option<Vec<WfcCell>> {
+        let all = u64::MAX >> (WFC_MAX_KINDS - self.kinds.len());
+        let size = (width * height) as usize;
+        let mut wave = vec![all; size];
+
+        // Undecided cells by how many options they have left, ties broken by a random number drawn once per cell
+        // Cells go back in whenever they narrow, so entries that don't match the wave anymore are skipped
+        let tiebreaks: Vec<u32> = (0..size).map(|_| rng.random()).collect();
+        let mut entropy: BinaryHeap<Reverse<(u32, u32, usize)>> = (0..size)
+            .map(|index| Reverse((all.count_ones(), tiebreaks[index], index)))
+            .collect();
+
+        // Find the undecided cell with the fewest options left
+        while let Some(index) = std::iter::from_fn(|| entropy.pop())
+            .map(|Reverse((options, _, index))| (options, index))
+            .find(|&(options, index)| options > 1 && wave[index].count_ones() == options)
+            .map(|(_, index)| index)
+        {
+            // Collapse it to one kind, weighted by how common that kind was in the sample
+            let options: Vec<usize> = (0..self.kinds.len())
+                .filter(|kind| wave[index] & (1 << kind) != 0)
+                .collect();
+            let total: u32 = options.iter().map(|&kind| self.weights[kind]).sum();
+            let mut pick = rng.random_range(0..total);
+            let mut chosen = options[0];
+            for &kind in &options {
+                if pick < self.weights[kind] {
+                    chosen = kind;
+                    break;
+                }
+                pick -= self.weights[kind];
+            }
+            wave[index] = 1 << chosen;
+
+            // Propagate the new constraint outwards until nothing changes
+            let mut stack = vec![index];
+            while let Some(index) = stack.pop() {
+                let (x, y) = (index as u32 % width, index as u32 / width);
+
+                for (direction, &(dx, dy)) in WFC_DIRECTIONS.iter().enumerate() {
+                    let (nx, ny) = (x as i32 + dx, y as i32 + dy);
+                    if nx < 0 || ny < 0 || nx >= width as i32 || ny >= height as i32 {
+                        continue;
+                    }
+
+                    let possible = (0..self.kinds.len())
+                        .filter(|kind| wave[index] & (1 << kind) != 0)
+                        .fold(0, |mask, kind| mask | self.allowed[direction][kind]);
+
+                    let neighbor = (ny as u32 * width + nx as u32) as usize;
+                    let narrowed = wave[neighbor] & possible;
+                    if narrowed == 0 {
+                        return None;
+                    }
+                    if narrowed != wave[neighbor] {
+                        wave[neighbor] = narrowed;
+                        stack.push(neighbor);
+                        entropy.push(Reverse((
+                            narrowed.count_ones(),
+                            tiebreaks[neighbor],
+                            neighbor,
+                        )));
+                    }
+                }
+            }
+        }
+
+        Some(
+            wave.into_iter()
+                .map(|mask| self.kinds[mask.trailing_zeros() as usize])
+                .collect(),
+        )
+    }
+}
+
+/**
+ * Synthesize a full map with the same local structure as the sample, returns false if no map could be made
+ */
+pub fn generate_wfc(
+    mut tile_states: Query<&mut TileState>,
+    mut tile_terrains: Query<&mut TileTerrain>,
+    storage: &TileStorage,
+    sample: &WfcSample,
+) -> bool {
+    let Some(rules) = WfcRules::learn(sample) else {
+        debug!("wfc sample has no usable tiles");
+        return false;
+    };
+
+    for attempt in 0..WFC_MAX_ATTEMPTS {
+        let seed = rng().random();
+        let mut seeded = StdRng::seed_from_u64(seed);
+
+        let Some(cells) = rules.collapse(MAP_SIZE, MAP_SIZE, &mut seeded) else {
+            debug!(
+                "wfc contradiction on attempt {} with seed {}",
+                attempt, seed
+            );
+            continue;
+        };
+
+        for (index, (state, terrain)) in cells.into_iter().enumerate() {
+            let pos = TilePos::new(index as u32 % MAP_SIZE, index as u32 / MAP_SIZE);
+            let entity = storage.checked_get(&pos).unwrap();
+            *tile_states.get_mut(entity).unwrap() = state;
+            *tile_terrains.get_mut(entity).unwrap() = terrain;
+        }
+
+        debug!("wfc finished on attempt {} with seed {}", attempt, seed);
+        return true;
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{SeedableRng, rngs::StdRng};
+
+    use super::*;
+
+    #[test]
+    fn collapse_follows_sample_adjacency() {
+        // In a checkerboard walls only ever touch plains, so anything collapsed from it has to be one too
+        let cells = (0..16)
+            .map(|index| match (index % 4 + index / 4) % 2 {
+                0 => (TileState::Wall, TileTerrain::Plains),
+                _ => (TileState::Empty, TileTerrain::Plains),
+            })
+            .collect();
+        let sample = WfcSample {
+            width: 4,
+            height: 4,
+            cells,
+        };
+        let rules = WfcRules::learn(&sample).unwrap();
+        let mut rng = StdRng::seed_from_u64(27);
+
+        let (width, height) = (12, 9);
+        let wave = rules.collapse(width, height, &mut rng).unwrap();
+
+        assert_eq!(wave.len(), (width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let cell = wave[(y * width + x) as usize];
+                if x + 1 < width {
+                    assert_ne!(cell, wave[(y * width + x + 1) as usize]);
+                }
+                if y + 1 < height {
+                    assert_ne!(cell, wave[((y + 1) * width + x) as usize]);
+                }
+            }
+        }
+    }
+}
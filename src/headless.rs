@@ -0,0 +1,196 @@
+use bevy::{log::LogPlugin, prelude::*};
+use bevy_ecs_tilemap::tiles::TileStorage;
+
+use crate::{
+    ActiveFloor, Floor, TileParent, TileState, TileTerrain,
+    algorithm::AlgorithmOption,
+    export::import_png,
+    pathfinder::{Pathfinder, pathfinder_plugin},
+    recorder::Recorder,
+    spawn_tiles,
+};
+
+const USAGE: &str = "usage: path_finding --headless <map.png> <out.gif> [--algorithm <name>] [--every <n>] [--pixels-per-tile <n>] [--trace <out.json>]";
+
+/**
+ * Settings for a run without a window, which loads a map, searches it to completion and records the result
+ */
+#[derive(Debug, Resource)]
+pub struct HeadlessArgs {
+    map_path: String,
+    gif_path: String,
+    algorithm: AlgorithmOption,
+    every: usize,
+    pixels_per_tile: u32,
+    trace_path: Option<String>,
+}
+
+impl HeadlessArgs {
+    /**
+     * Returns None when not asked to run headless, bad arguments print the usage and quit
+     */
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Option<Self> {
+        if args.next().as_deref() != Some("--headless") {
+            return None;
+        }
+
+        let (Some(map_path), Some(gif_path)) = (args.next(), args.next()) else {
+            fail("missing map or output path");
+        };
+
+        let mut parsed = Self {
+            map_path,
+            gif_path,
+            algorithm: AlgorithmOption::default(),
+            every: 1,
+            pixels_per_tile: 8,
+            trace_path: None,
+        };
+
+        while let Some(flag) = args.next() {
+            let Some(value) = args.next() else {
+                fail(&format!("missing value for {flag}"));
+            };
+
+            match flag.as_str() {
+                "--algorithm" => {
+                    parsed.algorithm = AlgorithmOption::from_name(&value)
+                        .unwrap_or_else(|| fail(&format!("unknown algorithm {value}")));
+                }
+                "--every" => {
+                    parsed.every = value
+                        .parse()
+                        .unwrap_or_else(|_| fail(&format!("invalid step count {value}")));
+                }
+                "--pixels-per-tile" => {
+                    parsed.pixels_per_tile = value
+                        .parse()
+                        .unwrap_or_else(|_| fail(&format!("invalid pixel count {value}")));
+                }
+                "--trace" => parsed.trace_path = Some(value),
+                _ => fail(&format!("unknown flag {flag}")),
+            }
+        }
+
+        Some(parsed)
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{message}\n{USAGE}");
+    std::process::exit(2);
+}
+
+pub fn run(args: HeadlessArgs) -> AppExit {
+    let mut recorder = Recorder::default();
+    recorder.enabled = true;
+    recorder.every = args.every;
+    recorder.pixels_per_tile = args.pixels_per_tile;
+
+    App::new()
+        .add_plugins((
+            MinimalPlugins,
+            LogPlugin {
+                filter: "info,path_finding=info".into(),
+                ..Default::default()
+            },
+            pathfinder_plugin,
+        ))
+        .insert_resource(recorder)
+        .insert_resource(args)
+        .add_systems(Startup, (spawn_map, import_map).chain())
+        .add_systems(
+            PostUpdate,
+            run_pathfinder.run_if(resource_exists::<MapLoaded>),
+        )
+        .run()
+}
+
+/**
+ * Marker that the map loaded correctly and the search can begin
+ */
+#[derive(Resource)]
+struct MapLoaded;
+
+/**
+ * Images only hold one floor, so headless runs use just the ground floor
+ */
+fn spawn_map(mut commands: Commands) {
+    let tilemap_entity = commands.spawn_empty().id();
+    let tile_storage = spawn_tiles(&mut commands, tilemap_entity, 0);
+    commands
+        .entity(tilemap_entity)
+        .insert((tile_storage, Floor(0), ActiveFloor));
+}
+
+fn import_map(
+    mut commands: Commands,
+    args: Res<HeadlessArgs>,
+    tile_states: Query<&mut TileState>,
+    tile_terrains: Query<&mut TileTerrain>,
+    storage: Single<&TileStorage>,
+    mut exit: MessageWriter<AppExit>,
+) {
+    match import_png(tile_states, tile_terrains, &storage, &args.map_path) {
+        Ok(()) => commands.insert_resource(MapLoaded),
+        Err(error) => {
+            error!("failed to import map: {}", error);
+            exit.write(AppExit::error());
+        }
+    }
+}
+
+/**
+ * Runs once after update_endpoints has seen the imported map, searching to completion in a single frame
+ */
+fn run_pathfinder(
+    args: Res<HeadlessArgs>,
+    mut pathfinder: ResMut<Pathfinder>,
+    mut recorder: ResMut<Recorder>,
+    mut tile_states: Query<&mut TileState>,
+    tile_terrains: Query<&TileTerrain>,
+    mut tile_parents: Query<&mut TileParent>,
+    storage: Single<&TileStorage>,
+    mut exit: MessageWriter<AppExit>,
+) {
+    pathfinder.restart(args.algorithm);
+    recorder.capture(
+        &pathfinder,
+        tile_states.as_readonly(),
+        tile_terrains.as_readonly(),
+        &storage,
+    );
+
+    while !pathfinder.complete {
+        pathfinder.step(&[*storage], tile_states.reborrow(), tile_parents.reborrow());
+        recorder.capture(
+            &pathfinder,
+            tile_states.as_readonly(),
+            tile_terrains.as_readonly(),
+            &storage,
+        );
+    }
+
+    info!(
+        "{} finished after {} steps, saving {} frames",
+        args.algorithm.name(),
+        pathfinder.step,
+        recorder.frame_count()
+    );
+
+    if let Some(trace_path) = &args.trace_path
+        && let Err(error) = pathfinder.trace.save(trace_path)
+    {
+        error!("failed to export trace: {}", error);
+        exit.write(AppExit::error());
+        return;
+    }
+
+    match recorder.save_gif(&args.gif_path) {
+        Ok(()) => exit.write(AppExit::Success),
+        Err(error) => {
+            error!("failed to save gif: {}", error);
+            exit.write(AppExit::error())
+        }
+    };
+}
@@ -0,0 +1,309 @@
+use bevy::prelude::*;
+use bevy_ecs_tilemap::prelude::*;
+use bevy_egui::input::egui_wants_any_input;
+
+use crate::{
+    TileParent, TileState, TileTerrain, generate::flush_path, is_search_state,
+    pathfinder::Pathfinder,
+};
+
+pub fn history_plugin(app: &mut App) {
+    app.init_resource::<History>()
+        .add_systems(Update, undo_redo.run_if(not(egui_wants_any_input)));
+}
+
+// Oldest edits are forgotten past this many
+const MAX_HISTORY: usize = 100;
+
+// Everything an edit can change about a tile, generators repaint the terrain along with the state
+pub type TileEdit = (TileState, TileTerrain);
+
+#[derive(Debug, Clone, Copy)]
+struct Change {
+    entity: Entity,
+    before: TileEdit,
+    after: TileEdit,
+}
+
+/**
+ * Undo and redo stacks of map edits, each entry is a whole stroke or generator action
+ */
+#[derive(Debug, Default, Resource)]
+pub struct History {
+    undo: Vec<Vec<Change>>,
+    redo: Vec<Vec<Change>>,
+    // Changes of the edit still in progress, like a stroke that hasn't been let go yet
+    pending: Vec<Change>,
+}
+
+impl History {
+    pub fn undo_len(&self) -> usize {
+        self.undo.len()
+    }
+
+    pub fn redo_len(&self) -> usize {
+        self.redo.len()
+    }
+
+    /**
+     * Add a tile change to the edit in progress, search progress counts as empty ground so undo never brings it back
+     */
+    pub fn record(&mut self, entity: Entity, before: TileEdit, after: TileEdit) {
+        let plain = |(state, terrain): TileEdit| {
+            if is_search_state(state) {
+                (TileState::Empty, terrain)
+            } else {
+                (state, terrain)
+            }
+        };
+        let (before, after) = (plain(before), plain(after));
+        if before != after {
+            self.pending.push(Change {
+                entity,
+                before,
+                after,
+            });
+        }
+    }
+
+    /**
+     * Close off the edit in progress as one undo step, a new edit throws away anything that could be redone
+     */
+    pub fn commit(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+
+        self.undo.push(std::mem::take(&mut self.pending));
+        if self.undo.len() > MAX_HISTORY {
+            self.undo.remove(0);
+        }
+        self.redo.clear();
+    }
+
+    /**
+     * Every tile state and terrain of a floor, to compare against once a generator has run
+     */
+    pub fn snapshot(
+        tile_states: Query<&TileState>,
+        tile_terrains: Query<&TileTerrain>,
+        storage: &TileStorage,
+    ) -> Vec<(Entity, TileEdit)> {
+        storage
+            .iter()
+            .flatten()
+            .map(|&entity| {
+                let state = *tile_states.get(entity).unwrap();
+                let terrain = *tile_terrains.get(entity).unwrap();
+                (entity, (state, terrain))
+            })
+            .collect()
+    }
+
+    /**
+     * Commit everything that's different from a snapshot as one undo step
+     */
+    pub fn commit_since(
+        &mut self,
+        snapshot: Vec<(Entity, TileEdit)>,
+        tile_states: Query<&TileState>,
+        tile_terrains: Query<&TileTerrain>,
+    ) {
+        for (entity, before) in snapshot {
+            let after = (
+                *tile_states.get(entity).unwrap(),
+                *tile_terrains.get(entity).unwrap(),
+            );
+            self.record(entity, before, after);
+        }
+        self.commit();
+    }
+
+    pub fn undo(
+        &mut self,
+        tile_states: Query<&mut TileState>,
+        tile_terrains: Query<&mut TileTerrain>,
+    ) -> bool {
+        let Some(changes) = self.undo.pop() else {
+            return false;
+        };
+
+        apply(changes.iter().rev(), tile_states, tile_terrains, |change| {
+            change.before
+        });
+        self.redo.push(changes);
+        true
+    }
+
+    pub fn redo(
+        &mut self,
+        tile_states: Query<&mut TileState>,
+        tile_terrains: Query<&mut TileTerrain>,
+    ) -> bool {
+        let Some(changes) = self.redo.pop() else {
+            return false;
+        };
+
+        apply(changes.iter(), tile_states, tile_terrains, |change| {
+            change.after
+        });
+        self.undo.push(changes);
+        true
+    }
+}
+
+/**
+ * Set every tile to one side of its changes, undo goes in reverse so tiles changed twice end up where they started
+ */
+fn apply<'a>(
+    changes: impl Iterator<Item = &'a Change>,
+    mut tile_states: Query<&mut TileState>,
+    mut tile_terrains: Query<&mut TileTerrain>,
+    side: impl Fn(&Change) -> TileEdit,
+) {
+    for change in changes {
+        let (state, terrain) = side(change);
+        tile_states
+            .get_mut(change.entity)
+            .unwrap()
+            .set_if_neq(state);
+        tile_terrains
+            .get_mut(change.entity)
+            .unwrap()
+            .set_if_neq(terrain);
+    }
+}
+
+/**
+ * Clear the search off the map after undoing or redoing, since it was run on tiles that aren't there anymore
+ */
+pub fn stop_search(
+    pathfinder: &mut Pathfinder,
+    tile_states: Query<&mut TileState>,
+    tile_parents: Query<&mut TileParent>,
+) {
+    flush_path(tile_states, tile_parents);
+    pathfinder.stop(pathfinder.option());
+}
+
+/**
+ * System that undoes with ctrl z and redoes with ctrl shift z
+ */
+fn undo_redo(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut history: ResMut<History>,
+    mut pathfinder: ResMut<Pathfinder>,
+    mut tile_states: Query<&mut TileState>,
+    mut tile_terrains: Query<&mut TileTerrain>,
+    tile_parents: Query<&mut TileParent>,
+) {
+    let ctrl = keyboard.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]);
+    let shift = keyboard.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]);
+    if !ctrl || !keyboard.just_pressed(KeyCode::KeyZ) {
+        return;
+    }
+
+    let done = if shift {
+        history.redo(tile_states.reborrow(), tile_terrains.reborrow())
+    } else {
+        history.undo(tile_states.reborrow(), tile_terrains.reborrow())
+    };
+    if done {
+        stop_search(&mut pathfinder, tile_states, tile_parents);
+    } else {
+        debug!("nothing to {}", if shift { "redo" } else { "undo" });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::SystemState;
+
+    use super::*;
+
+    type Tiles = SystemState<(
+        Query<'static, 'static, &'static mut TileState>,
+        Query<'static, 'static, &'static mut TileTerrain>,
+    )>;
+
+    fn tile(world: &World, entity: Entity) -> TileEdit {
+        (
+            *world.get::<TileState>(entity).unwrap(),
+            *world.get::<TileTerrain>(entity).unwrap(),
+        )
+    }
+
+    #[test]
+    fn undo_redo_round_trip() {
+        let mut world = World::new();
+        let painted = world.spawn((TileState::Wall, TileTerrain::Plains)).id();
+        let searched = world.spawn((TileState::Wall, TileTerrain::Forest)).id();
+        let regrown = world.spawn((TileState::Empty, TileTerrain::Mountains)).id();
+        let mut tiles = Tiles::new(&mut world);
+
+        // The map already looks like it does after both edits, a stroke of walls and then a generator changing terrain
+        let mut history = History::default();
+        history.record(
+            painted,
+            (TileState::Empty, TileTerrain::Plains),
+            (TileState::Wall, TileTerrain::Plains),
+        );
+        history.record(
+            searched,
+            (TileState::Visited(3), TileTerrain::Forest),
+            (TileState::Wall, TileTerrain::Forest),
+        );
+        history.commit();
+        history.record(
+            regrown,
+            (TileState::Start, TileTerrain::Plains),
+            (TileState::Empty, TileTerrain::Mountains),
+        );
+        history.commit();
+        assert_eq!((history.undo_len(), history.redo_len()), (2, 0));
+
+        let (states, terrains) = tiles.get_mut(&mut world);
+        assert!(history.undo(states, terrains));
+        assert_eq!(
+            tile(&world, regrown),
+            (TileState::Start, TileTerrain::Plains)
+        );
+        assert_eq!(
+            tile(&world, painted),
+            (TileState::Wall, TileTerrain::Plains)
+        );
+
+        let (states, terrains) = tiles.get_mut(&mut world);
+        assert!(history.undo(states, terrains));
+        assert_eq!(
+            tile(&world, painted),
+            (TileState::Empty, TileTerrain::Plains)
+        );
+        // Search progress comes back as empty ground, keeping its terrain
+        assert_eq!(
+            tile(&world, searched),
+            (TileState::Empty, TileTerrain::Forest)
+        );
+
+        let (states, terrains) = tiles.get_mut(&mut world);
+        assert!(!history.undo(states, terrains));
+
+        for _ in 0..2 {
+            let (states, terrains) = tiles.get_mut(&mut world);
+            assert!(history.redo(states, terrains));
+        }
+        assert_eq!(
+            tile(&world, painted),
+            (TileState::Wall, TileTerrain::Plains)
+        );
+        assert_eq!(
+            tile(&world, searched),
+            (TileState::Wall, TileTerrain::Forest)
+        );
+        assert_eq!(
+            tile(&world, regrown),
+            (TileState::Empty, TileTerrain::Mountains)
+        );
+        assert_eq!((history.undo_len(), history.redo_len()), (2, 0));
+    }
+}
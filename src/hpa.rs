@@ -0,0 +1,548 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet, VecDeque},
+};
+
+use bevy::{color::palettes::basic, prelude::*};
+use bevy_ecs_tilemap::prelude::*;
+
+use crate::{
+    ActiveFloor, MAP_SIZE, TileState,
+    smoothing::{blocks_sight, tile_distance},
+    tile_center,
+};
+
+pub fn hpa_plugin(app: &mut App) {
+    app.init_resource::<Hpa>()
+        .add_systems(Update, draw_hpa_overlay);
+}
+
+// Entrances at least this wide get a transition at each end instead of one in the middle
+const WIDE_ENTRANCE: u32 = 6;
+
+/**
+ * Hierarchical pathfinding, which plans over a small graph of cluster entrances and then fills in the tiles between
+ */
+#[derive(Debug, Resource)]
+pub struct Hpa {
+    pub cluster_size: u32,
+    pub overlay: bool,
+
+    graph: Option<AbstractGraph>,
+    // Entrances the last plan went through, then every tile of the refined path
+    pub abstract_path: Vec<TilePos>,
+    pub path: Vec<TilePos>,
+    pub expansions: usize,
+}
+
+impl Default for Hpa {
+    fn default() -> Self {
+        Self {
+            cluster_size: 8,
+            overlay: true,
+
+            graph: None,
+            abstract_path: Vec::new(),
+            path: Vec::new(),
+            expansions: 0,
+        }
+    }
+}
+
+impl Hpa {
+    /**
+     * Rebuild the abstract graph from the current floor and plan from its first start to its closest goal,
+     * returns false if there's no start, goal or path
+     * Portals, keys and other floors are ignored, and tiles that only block sometimes are treated as walls
+     */
+    pub fn plan(&mut self, tile_states: Query<&TileState>, storage: &TileStorage) -> bool {
+        let state = |pos: TilePos| *tile_states.get(storage.checked_get(&pos).unwrap()).unwrap();
+        let walkable = |pos: TilePos| !blocks_sight(state(pos));
+
+        let graph = AbstractGraph::build(&walkable, self.cluster_size);
+        debug!(
+            "hpa graph has {} entrances and {} nodes in {} clusters",
+            graph.entrances,
+            graph.edges.len(),
+            MAP_SIZE.div_ceil(self.cluster_size).pow(2)
+        );
+
+        self.abstract_path.clear();
+        self.path.clear();
+        self.expansions = 0;
+
+        let positions = (0..MAP_SIZE).flat_map(|y| (0..MAP_SIZE).map(move |x| TilePos::new(x, y)));
+        let start = positions
+            .clone()
+            .find(|&pos| state(pos) == TileState::Start);
+        let goals = positions.filter(|&pos| state(pos) == TileState::Goal);
+
+        let planned = start.and_then(|start| {
+            goals
+                .filter_map(|goal| graph.plan(start, goal, &walkable))
+                .min_by_key(|plan| plan.path.len())
+        });
+        self.graph = Some(graph);
+
+        let Some(plan) = planned else {
+            debug!("hpa found no path");
+            return false;
+        };
+
+        debug!(
+            "hpa found a path of {} tiles through {} entrances after {} expansions",
+            plan.path.len(),
+            plan.abstract_path.len(),
+            plan.expansions
+        );
+        self.abstract_path = plan.abstract_path;
+        self.path = plan.path;
+        self.expansions = plan.expansions;
+        true
+    }
+
+    /**
+     * Color the refined path like the pathfinder's own final paths
+     */
+    pub fn mark_path(&self, mut tile_states: Query<&mut TileState>, storage: &TileStorage) {
+        let Some(&goal) = self.path.last() else {
+            return;
+        };
+
+        for &pos in &self.path {
+            let mut tile_state = tile_states
+                .get_mut(storage.checked_get(&pos).unwrap())
+                .unwrap();
+            if *tile_state == TileState::Empty {
+                *tile_state = TileState::Final(tile_distance(pos, goal) as u32);
+            }
+        }
+    }
+
+    /**
+     * Open stretches of cluster border, each one is one or two transitions
+     */
+    pub fn entrance_count(&self) -> usize {
+        self.graph.as_ref().map_or(0, |graph| graph.entrances)
+    }
+
+    /**
+     * Tiles of the abstract graph, one on each side of every transition
+     */
+    pub fn node_count(&self) -> usize {
+        self.graph.as_ref().map_or(0, |graph| graph.edges.len())
+    }
+
+    pub fn clear(&mut self) {
+        self.graph = None;
+        self.abstract_path.clear();
+        self.path.clear();
+        self.expansions = 0;
+    }
+}
+
+#[derive(Debug)]
+struct AbstractEdge {
+    to: TilePos,
+    cost: u32,
+    // Every tile walked after leaving, ending on `to`
+    path: Vec<TilePos>,
+    // Crosses between clusters rather than through one
+    inter: bool,
+}
+
+#[derive(Debug)]
+struct AbstractGraph {
+    cluster_size: u32,
+    entrances: usize,
+    edges: HashMap<TilePos, Vec<AbstractEdge>>,
+}
+
+/**
+ * Parents from a search inside one cluster, for finding the way to any tile it reached
+ */
+#[derive(Debug)]
+struct ClusterPaths {
+    from: TilePos,
+    parents: HashMap<TilePos, TilePos>,
+}
+
+impl ClusterPaths {
+    /**
+     * Every tile walked after leaving, ending on `to`
+     */
+    fn path_to(&self, to: TilePos) -> Option<Vec<TilePos>> {
+        self.parents.get(&to)?;
+
+        let mut path = vec![to];
+        let mut current = to;
+        while let Some(&parent) = self.parents.get(&current)
+            && parent != self.from
+        {
+            path.push(parent);
+            current = parent;
+        }
+        path.reverse();
+        Some(path)
+    }
+}
+
+#[derive(Debug)]
+struct Plan {
+    abstract_path: Vec<TilePos>,
+    path: Vec<TilePos>,
+    expansions: usize,
+}
+
+impl AbstractGraph {
+    fn build(walkable: &impl Fn(TilePos) -> bool, cluster_size: u32) -> Self {
+        let mut graph = Self {
+            cluster_size,
+            entrances: 0,
+            edges: HashMap::new(),
+        };
+
+        // Find entrances along every border between two clusters
+        for border in (cluster_size..MAP_SIZE).step_by(cluster_size as usize) {
+            for start in (0..MAP_SIZE).step_by(cluster_size as usize) {
+                let along = start..(start + cluster_size).min(MAP_SIZE);
+
+                // Vertical border, left and right sides
+                graph.add_entrances(along.clone(), walkable, |i| {
+                    (TilePos::new(border - 1, i), TilePos::new(border, i))
+                });
+                // Horizontal border, bottom and top sides
+                graph.add_entrances(along, walkable, |i| {
+                    (TilePos::new(i, border - 1), TilePos::new(i, border))
+                });
+            }
+        }
+
+        // Then connect the entrances inside each cluster
+        let nodes: Vec<TilePos> = graph.edges.keys().copied().collect();
+        for &from in &nodes {
+            let reached = graph.cluster_paths(from, walkable);
+            for &to in &nodes {
+                if let Some(path) = reached.path_to(to) {
+                    graph.edges.get_mut(&from).unwrap().push(AbstractEdge {
+                        to,
+                        cost: path.len() as u32,
+                        path,
+                        inter: false,
+                    });
+                }
+            }
+        }
+
+        graph
+    }
+
+    /**
+     * Walk one border, adding transitions for every open stretch where both sides are walkable
+     */
+    fn add_entrances(
+        &mut self,
+        along: std::ops::Range<u32>,
+        walkable: &impl Fn(TilePos) -> bool,
+        sides: impl Fn(u32) -> (TilePos, TilePos),
+    ) {
+        let open = |i: u32| {
+            let (a, b) = sides(i);
+            walkable(a) && walkable(b)
+        };
+
+        let mut i = along.start;
+        while i < along.end {
+            if !open(i) {
+                i += 1;
+                continue;
+            }
+
+            let run_start = i;
+            while i < along.end && open(i) {
+                i += 1;
+            }
+            let run_end = i - 1;
+            self.entrances += 1;
+
+            if run_end - run_start + 1 >= WIDE_ENTRANCE {
+                self.add_transition(sides(run_start));
+                self.add_transition(sides(run_end));
+            } else {
+                self.add_transition(sides((run_start + run_end) / 2));
+            }
+        }
+    }
+
+    fn add_transition(&mut self, (a, b): (TilePos, TilePos)) {
+        for (from, to) in [(a, b), (b, a)] {
+            self.edges.entry(from).or_default().push(AbstractEdge {
+                to,
+                cost: 1,
+                path: vec![to],
+                inter: true,
+            });
+        }
+    }
+
+    fn cluster(&self, pos: TilePos) -> (u32, u32) {
+        (pos.x / self.cluster_size, pos.y / self.cluster_size)
+    }
+
+    /**
+     * Breadth first search that stays inside the cluster of `from`
+     */
+    fn cluster_paths(&self, from: TilePos, walkable: &impl Fn(TilePos) -> bool) -> ClusterPaths {
+        let cluster = self.cluster(from);
+        let mut parents: HashMap<TilePos, TilePos> = HashMap::new();
+        let mut queue = VecDeque::from([from]);
+
+        while let Some(pos) = queue.pop_front() {
+            for (dx, dy) in [(1, 0), (-1, 0), (0, 1), (0, -1)] {
+                let (x, y) = (pos.x as i32 + dx, pos.y as i32 + dy);
+                if x < 0 || y < 0 || x >= MAP_SIZE as i32 || y >= MAP_SIZE as i32 {
+                    continue;
+                }
+
+                let next = TilePos::new(x as u32, y as u32);
+                if self.cluster(next) == cluster
+                    && walkable(next)
+                    && next != from
+                    && !parents.contains_key(&next)
+                {
+                    parents.insert(next, pos);
+                    queue.push_back(next);
+                }
+            }
+        }
+
+        ClusterPaths { from, parents }
+    }
+
+    /**
+     * Search the abstract graph with the start and goal temporarily hooked up to their clusters' entrances,
+     * then refine by stitching together the cached paths of every edge taken
+     */
+    fn plan(
+        &self,
+        start: TilePos,
+        goal: TilePos,
+        walkable: &impl Fn(TilePos) -> bool,
+    ) -> Option<Plan> {
+        let from_start = self.cluster_paths(start, walkable);
+        let to_goal = self.cluster_paths(goal, walkable);
+
+        // Same cluster, so there's no need for the abstract graph if they're connected inside it
+        if self.cluster(start) == self.cluster(goal)
+            && let Some(path) = from_start.path_to(goal)
+        {
+            return Some(Plan {
+                abstract_path: Vec::new(),
+                path,
+                expansions: 0,
+            });
+        }
+
+        let start_edges: Vec<AbstractEdge> = self
+            .edges
+            .keys()
+            .filter(|&&node| self.cluster(node) == self.cluster(start))
+            .filter_map(|&node| {
+                let path = from_start.path_to(node)?;
+                Some(AbstractEdge {
+                    to: node,
+                    cost: path.len() as u32,
+                    path,
+                    inter: false,
+                })
+            })
+            .collect();
+
+        // The goal's cluster is searched from the goal, so those paths get turned around
+        let goal_edge = |node: TilePos| {
+            if self.cluster(node) != self.cluster(goal) {
+                return None;
+            }
+            let mut path = to_goal.path_to(node)?;
+            path.pop();
+            path.reverse();
+            path.push(goal);
+            Some(AbstractEdge {
+                to: goal,
+                cost: path.len() as u32,
+                path,
+                inter: false,
+            })
+        };
+
+        let heuristic = |pos: TilePos| pos.x.abs_diff(goal.x) + pos.y.abs_diff(goal.y);
+
+        let mut costs: HashMap<TilePos, u32> = HashMap::from([(start, 0)]);
+        let mut parents: HashMap<TilePos, (TilePos, Vec<TilePos>)> = HashMap::new();
+        let mut queue = BinaryHeap::from([Reverse((heuristic(start), start.x, start.y))]);
+        let mut closed = HashSet::new();
+        let mut expansions = 0;
+
+        while let Some(Reverse((_, x, y))) = queue.pop() {
+            let node = TilePos::new(x, y);
+            if !closed.insert(node) {
+                continue;
+            }
+            expansions += 1;
+
+            if node == goal {
+                break;
+            }
+
+            let goal_edge = goal_edge(node);
+            let edges = self
+                .edges
+                .get(&node)
+                .into_iter()
+                .flatten()
+                .chain(if node == start {
+                    &start_edges[..]
+                } else {
+                    &[][..]
+                })
+                .chain(goal_edge.as_ref());
+
+            for edge in edges {
+                let cost = costs[&node] + edge.cost;
+                if costs.get(&edge.to).is_some_and(|&old| old <= cost) {
+                    continue;
+                }
+
+                costs.insert(edge.to, cost);
+                parents.insert(edge.to, (node, edge.path.clone()));
+                queue.push(Reverse((cost + heuristic(edge.to), edge.to.x, edge.to.y)));
+            }
+        }
+
+        // Walk the parents back, collecting entrances and the tiles between them
+        parents.get(&goal)?;
+        let mut abstract_path = Vec::new();
+        let mut path = Vec::new();
+        let mut current = goal;
+        while let Some((parent, segment)) = parents.get(&current) {
+            path.extend(segment.iter().rev());
+            if *parent != start {
+                abstract_path.push(*parent);
+            }
+            current = *parent;
+        }
+        abstract_path.reverse();
+        path.reverse();
+
+        Some(Plan {
+            abstract_path,
+            path,
+            expansions,
+        })
+    }
+}
+
+/**
+ * System that draws cluster borders, entrances and the abstract graph over the current floor
+ */
+fn draw_hpa_overlay(
+    mut gizmos: Gizmos,
+    hpa: Res<Hpa>,
+    tilemap: Single<
+        (
+            &TilemapSize,
+            &TilemapGridSize,
+            &TilemapTileSize,
+            &TilemapType,
+            &TilemapAnchor,
+            &Transform,
+        ),
+        With<ActiveFloor>,
+    >,
+) {
+    let Some(graph) = &hpa.graph else {
+        return;
+    };
+    if !hpa.overlay {
+        return;
+    }
+
+    // Tile corners, found by stepping from the first tile's center
+    let origin = tile_center(*tilemap, TilePos::new(0, 0));
+    let step = tile_center(*tilemap, TilePos::new(1, 1)) - origin;
+    let corner = |x: u32, y: u32| origin + (Vec2::new(x as f32, y as f32) - 0.5) * step;
+
+    for border in (graph.cluster_size..MAP_SIZE).step_by(graph.cluster_size as usize) {
+        gizmos.line_2d(corner(border, 0), corner(border, MAP_SIZE), basic::WHITE);
+        gizmos.line_2d(corner(0, border), corner(MAP_SIZE, border), basic::WHITE);
+    }
+
+    for (&from, edges) in &graph.edges {
+        gizmos.circle_2d(tile_center(*tilemap, from), step.x / 4.0, basic::YELLOW);
+
+        for edge in edges {
+            let color = if edge.inter {
+                basic::YELLOW
+            } else {
+                basic::OLIVE
+            };
+            gizmos.line_2d(
+                tile_center(*tilemap, from),
+                tile_center(*tilemap, edge.to),
+                color,
+            );
+        }
+    }
+
+    gizmos.linestrip_2d(
+        hpa.abstract_path
+            .iter()
+            .map(|&pos| tile_center(*tilemap, pos)),
+        basic::FUCHSIA,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn adjacent(a: TilePos, b: TilePos) -> bool {
+        a.x.abs_diff(b.x) + a.y.abs_diff(b.y) == 1
+    }
+
+    #[test]
+    fn open_map_entrances() {
+        // Every stretch of border is one entrance, three borders each way with four clusters along them
+        let graph = AbstractGraph::build(&|_| true, 8);
+
+        assert_eq!(graph.entrances, 3 * 4 * 2);
+    }
+
+    #[test]
+    fn refined_path_is_walkable() {
+        // A wall across the map with a single gap, forcing the path through one entrance
+        let walkable = |pos: TilePos| pos.x != 12 || pos.y == 20;
+        let graph = AbstractGraph::build(&walkable, 8);
+        let (start, goal) = (TilePos::new(2, 2), TilePos::new(28, 3));
+
+        let plan = graph.plan(start, goal, &walkable).unwrap();
+
+        assert_eq!(plan.path.last(), Some(&goal));
+        assert!(plan.path.contains(&TilePos::new(12, 20)));
+        assert!(plan.path.iter().all(|&pos| walkable(pos)));
+        assert!(adjacent(start, plan.path[0]));
+        assert!(plan.path.windows(2).all(|step| adjacent(step[0], step[1])));
+    }
+
+    #[test]
+    fn walled_off_goal_has_no_plan() {
+        let walkable = |pos: TilePos| pos.x != 12;
+        let graph = AbstractGraph::build(&walkable, 8);
+
+        assert!(
+            graph
+                .plan(TilePos::new(2, 2), TilePos::new(28, 3), &walkable)
+                .is_none()
+        );
+    }
+}
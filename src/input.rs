@@ -5,54 +5,112 @@ use bevy::{
 use bevy_ecs_tilemap::prelude::*;
 use bevy_egui::input::egui_wants_any_input;
 
-use crate::TileState;
+use crate::{
+    ActiveFloor, CurrentFloor, FLOOR_COUNT, TileState, TileTerrain,
+    brush::Brush,
+    history::History,
+    pathfinder_tile::{Cardinal, KeyColor},
+};
 
 pub fn input_plugin(app: &mut App) {
-    app.init_resource::<CursorPos>().add_systems(
-        Update,
-        (movement, zoom, cursor_pos, mouse_paint).run_if(not(egui_wants_any_input)),
-    );
+    app.init_resource::<CursorPos>()
+        .init_resource::<PortalPlacer>()
+        .add_systems(
+            Update,
+            (movement, zoom, cursor_pos, mouse_paint, change_floor)
+                .run_if(not(egui_wants_any_input)),
+        );
 }
 
-fn mouse_paint(
-    cursor_pos: Res<CursorPos>,
+/**
+ * Keeps track of which portal pair is being placed, each pair gets the next number once both ends are down
+ */
+#[derive(Debug, Default, Resource)]
+pub struct PortalPlacer {
+    pair: u32,
+    placed: u32,
+}
 
-    mut tile_states: Query<&mut TileState>,
-    mouse: Res<ButtonInput<MouseButton>>,
-    keyboard: Res<ButtonInput<KeyCode>>,
-    tilemap: Single<(
+/**
+ * The tile under a world position on a tile map, if there is one
+ */
+pub fn tile_at(
+    world_pos: Vec2,
+    (map_size, grid_size, tile_size, map_type, anchor, map_transform): (
         &TilemapSize,
         &TilemapGridSize,
         &TilemapTileSize,
         &TilemapType,
         &TilemapAnchor,
-        &TileStorage,
         &Transform,
-    )>,
-) {
-    // Most of these are needed for the TilePos::from_world_pos later on
-    let (map_size, grid_size, tile_size, map_type, anchor, tile_storage, map_transform) = *tilemap;
-
-    // Grab the cursor position from the `Res<CursorPos>`
-    let cursor_pos: Vec2 = cursor_pos.0;
-
+    ),
+) -> Option<TilePos> {
     // We need to make sure that the cursor's world position is correct relative to the map due to any map transformation.
     let cursor_in_map_pos: Vec2 = {
         // Extend the cursor_pos vec3 by 0.0 and 1.0
-        let cursor_pos = Vec4::from((cursor_pos, 0.0, 1.0));
+        let cursor_pos = Vec4::from((world_pos, 0.0, 1.0));
         let cursor_in_map_pos = map_transform.to_matrix().inverse() * cursor_pos;
         cursor_in_map_pos.xy()
     };
 
     // Once we have a world position we can transform it into a possible tile position.
-    let Some(tile_pos) = TilePos::from_world_pos(
+    TilePos::from_world_pos(
         &cursor_in_map_pos,
         map_size,
         grid_size,
         tile_size,
         map_type,
         anchor,
-    ) else {
+    )
+}
+
+/**
+ * System that paints with the brush while a mouse button is down, and places single tiles from the keyboard
+ */
+fn mouse_paint(
+    cursor_pos: Res<CursorPos>,
+    mut portal_placer: ResMut<PortalPlacer>,
+    mut brush: ResMut<Brush>,
+    mut history: ResMut<History>,
+
+    mut tile_states: Query<&mut TileState>,
+    tile_terrains: Query<&TileTerrain>,
+    mouse: Res<ButtonInput<MouseButton>>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+    tilemap: Single<
+        (
+            (
+                &TilemapSize,
+                &TilemapGridSize,
+                &TilemapTileSize,
+                &TilemapType,
+                &TilemapAnchor,
+                &Transform,
+            ),
+            &TileStorage,
+        ),
+        With<ActiveFloor>,
+    >,
+) {
+    let (layout, tile_storage) = *tilemap;
+
+    let hovered = tile_at(cursor_pos.0, layout);
+
+    let state_at = |pos: TilePos| *tile_states.get(tile_storage.get(&pos).unwrap()).unwrap();
+    for (pos, paint) in brush.update(&mouse, hovered, state_at) {
+        let entity = tile_storage.get(&pos).unwrap();
+        let mut state = tile_states.get_mut(entity).unwrap();
+        let terrain = *tile_terrains.get(entity).unwrap();
+        history.record(entity, (*state, terrain), (paint, terrain));
+        state.set_if_neq(paint);
+    }
+
+    // A stroke is one undo step however many frames it took
+    if !brush.painting() {
+        history.commit();
+    }
+
+    let Some(tile_pos) = hovered else {
         return;
     };
 
@@ -62,14 +120,7 @@ fn mouse_paint(
     };
 
     let mut tile_state = tile_states.get_mut(tile_entity).unwrap();
-
-    if mouse.pressed(MouseButton::Left) {
-        *tile_state = TileState::Wall;
-    };
-
-    if mouse.pressed(MouseButton::Right) {
-        *tile_state = TileState::Empty;
-    }
+    let before = *tile_state;
 
     if keyboard.just_pressed(KeyCode::KeyS) {
         *tile_state = TileState::Start;
@@ -78,10 +129,72 @@ fn mouse_paint(
     if keyboard.just_pressed(KeyCode::KeyE) {
         *tile_state = TileState::Goal;
     }
+
+    for (key, direction) in [
+        (KeyCode::KeyI, Cardinal::North),
+        (KeyCode::KeyL, Cardinal::East),
+        (KeyCode::KeyK, Cardinal::South),
+        (KeyCode::KeyJ, Cardinal::West),
+    ] {
+        if keyboard.just_pressed(key) {
+            *tile_state = TileState::OneWay(direction);
+        }
+    }
+
+    // Number keys place keys, holding shift places their doors instead
+    let shift = keyboard.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]);
+    for (key, color) in [KeyCode::Digit1, KeyCode::Digit2, KeyCode::Digit3]
+        .into_iter()
+        .zip(KeyColor::ALL)
+    {
+        if keyboard.just_pressed(key) {
+            *tile_state = if shift {
+                TileState::Door(color)
+            } else {
+                TileState::Key(color)
+            };
+        }
+    }
+
+    if keyboard.just_pressed(KeyCode::KeyH) {
+        *tile_state = TileState::Stairs;
+    }
+
+    if keyboard.just_pressed(KeyCode::KeyP) {
+        *tile_state = TileState::Portal(portal_placer.pair);
+
+        portal_placer.placed += 1;
+        if portal_placer.placed == 2 {
+            portal_placer.placed = 0;
+            portal_placer.pair += 1;
+        }
+    }
+
+    if *tile_state != before {
+        let terrain = *tile_terrains.get(tile_entity).unwrap();
+        history.record(tile_entity, (before, terrain), (*tile_state, terrain));
+        // Mid stroke the placement joins the stroke's undo step instead
+        if !brush.painting() {
+            history.commit();
+        }
+    }
+}
+
+/**
+ * System that moves between floors with page up and page down
+ */
+fn change_floor(keyboard: Res<ButtonInput<KeyCode>>, mut current_floor: ResMut<CurrentFloor>) {
+    if keyboard.just_pressed(KeyCode::PageUp) && current_floor.0 + 1 < FLOOR_COUNT {
+        current_floor.0 += 1;
+    }
+
+    if keyboard.just_pressed(KeyCode::PageDown) && current_floor.0 > 0 {
+        current_floor.0 -= 1;
+    }
 }
 
 #[derive(Resource)]
-pub struct CursorPos(Vec2);
+pub struct CursorPos(pub Vec2);
 impl Default for CursorPos {
     fn default() -> Self {
         // Initialize the cursor pos at some far away place. It will get updated
@@ -0,0 +1,140 @@
+use bevy::prelude::*;
+use bevy_ecs_tilemap::prelude::*;
+use bevy_egui::{
+    EguiContexts, EguiPrimaryContextPass,
+    egui::{Grid, RichText, Window},
+};
+
+use crate::{
+    ActiveFloor, CurrentFloor, TileParent, TileState,
+    input::{CursorPos, tile_at},
+    pathfinder::Pathfinder,
+};
+
+pub fn inspector_plugin(app: &mut App) {
+    app.init_resource::<Inspector>()
+        .add_systems(EguiPrimaryContextPass, inspect_tile);
+}
+
+/**
+ * Shows what the search knows about the tile under the cursor
+ */
+#[derive(Debug, Resource)]
+pub struct Inspector {
+    pub enabled: bool,
+}
+
+impl Default for Inspector {
+    fn default() -> Self {
+        Self { enabled: true }
+    }
+}
+
+/**
+ * System that fills the inspector window in for the hovered tile
+ */
+fn inspect_tile(
+    mut contexts: EguiContexts,
+    inspector: Res<Inspector>,
+    pathfinder: Res<Pathfinder>,
+    cursor_pos: Res<CursorPos>,
+    current: Res<CurrentFloor>,
+    tiles: Query<(&TileState, &TileParent)>,
+    tilemap: Single<
+        (
+            (
+                &TilemapSize,
+                &TilemapGridSize,
+                &TilemapTileSize,
+                &TilemapType,
+                &TilemapAnchor,
+                &Transform,
+            ),
+            &TileStorage,
+        ),
+        With<ActiveFloor>,
+    >,
+) {
+    if !inspector.enabled {
+        return;
+    }
+
+    let (layout, storage) = *tilemap;
+    let hovered = tile_at(cursor_pos.0, layout);
+
+    Window::new("Inspector").show(contexts.ctx_mut().unwrap(), |ui| {
+        let Some(pos) = hovered else {
+            ui.label("Hover over a tile");
+            return;
+        };
+
+        let (state, parent) = tiles.get(storage.checked_get(&pos).unwrap()).unwrap();
+        let record = pathfinder.record(pos, current.0);
+        let or_dash = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+
+        let rows = [
+            (
+                "Position",
+                format!("{}, {} on floor {}", pos.x, pos.y, current.0),
+            ),
+            ("State", format!("{:?}", state)),
+            (
+                "Heuristic",
+                record
+                    .map_or_else(
+                        || pathfinder.heuristic(pos, storage.size),
+                        |record| record.distance,
+                    )
+                    .to_string(),
+            ),
+            (
+                "Cost So Far",
+                or_dash(
+                    record
+                        .and_then(|record| record.cost)
+                        .map(|cost| format!("{:.2}", cost)),
+                ),
+            ),
+            (
+                "F Score",
+                or_dash(
+                    record
+                        .and_then(|record| record.score())
+                        .map(|score| format!("{:.2}", score)),
+                ),
+            ),
+            (
+                "Parent",
+                or_dash(parent.0.map(|parent| format!("{}, {}", parent.x, parent.y))),
+            ),
+            (
+                "Queued On Step",
+                or_dash(
+                    record
+                        .and_then(|record| record.queued)
+                        .map(|step| step.to_string()),
+                ),
+            ),
+            (
+                "Visited On Step",
+                or_dash(
+                    record
+                        .and_then(|record| record.visited)
+                        .map(|step| step.to_string()),
+                ),
+            ),
+            (
+                "Expansions",
+                record.map_or(0, |record| record.expansions).to_string(),
+            ),
+        ];
+
+        Grid::new("inspector").show(ui, |ui| {
+            for (label, value) in rows {
+                ui.label(RichText::new(label).strong());
+                ui.label(value);
+                ui.end_row();
+            }
+        });
+    });
+}
@@ -1,18 +1,56 @@
+// Bevy systems take many parameters with long query types, these are the same lints Bevy allows for itself
+#![allow(clippy::type_complexity, clippy::too_many_arguments)]
+
 mod algorithm;
+mod brush;
+mod export;
+mod flow_field;
+mod frontier;
 mod generate;
+mod headless;
+mod history;
+mod hpa;
 mod input;
+mod inspector;
+mod mapf;
+mod obstacles;
 mod options;
+mod overlay;
 mod pathfinder;
 mod pathfinder_tile;
+mod pursuit;
+mod recorder;
+mod smoothing;
+mod trace;
+mod walker;
 
 use bevy::{asset::embedded_asset, log::LogPlugin, prelude::*, window::PrimaryWindow};
 use bevy_ecs_tilemap::prelude::*;
 use bevy_egui::EguiPlugin;
+use brush::brush_plugin;
+use flow_field::flow_field_plugin;
+use frontier::frontier_plugin;
+use history::history_plugin;
+use hpa::hpa_plugin;
 use input::input_plugin;
+use inspector::inspector_plugin;
+use mapf::mapf_plugin;
+use obstacles::obstacles_plugin;
 use options::options_plugin;
-use pathfinder::pathfinder_plugin;
+use overlay::{ColorMode, Overlay, overlay_plugin};
+use pathfinder::{Pathfinder, pathfinder_plugin};
+use pathfinder_tile::{Cardinal, KeyColor};
+use pursuit::pursuit_plugin;
+use recorder::recorder_plugin;
+use smoothing::smoothing_plugin;
+use std::collections::HashSet;
+use walker::walker_plugin;
 
 fn main() -> AppExit {
+    if let Some(args) = headless::HeadlessArgs::parse(std::env::args().skip(1)) {
+        return headless::run(args);
+    }
+
     let mut app = App::new();
 
     app.add_plugins((
@@ -23,12 +61,40 @@ fn main() -> AppExit {
         }),
         TilemapPlugin,
         EguiPlugin::default(),
-        input_plugin,
-        pathfinder_plugin,
+        // Grouped since plugin tuples only go up to 15
+        (
+            input_plugin,
+            brush_plugin,
+            history_plugin,
+            inspector_plugin,
+            overlay_plugin,
+            frontier_plugin,
+        ),
+        (
+            pathfinder_plugin,
+            recorder_plugin,
+            smoothing_plugin,
+            hpa_plugin,
+            flow_field_plugin,
+            mapf_plugin,
+            walker_plugin,
+            pursuit_plugin,
+            obstacles_plugin,
+        ),
         options_plugin,
     ))
+    .init_resource::<CurrentFloor>()
     .add_systems(Startup, startup)
-    .add_systems(Update, color_tile);
+    .add_systems(
+        Update,
+        (
+            color_tile,
+            show_current_floor,
+            draw_wrap_edges,
+            draw_portal_links,
+            draw_floor_changes,
+        ),
+    );
 
     embedded_asset!(app, "../assets/tiles.png");
 
@@ -36,6 +102,7 @@ fn main() -> AppExit {
 }
 
 pub const MAP_SIZE: u32 = 32;
+pub const FLOOR_COUNT: u32 = 3;
 
 fn startup(
     mut commands: Commands,
@@ -54,7 +121,44 @@ fn startup(
         y: MAP_SIZE,
     };
 
-    let tilemap_entity = commands.spawn_empty().id();
+    let tile_size = TilemapTileSize { x: 16.0, y: 16.0 };
+    let grid_size = tile_size.into();
+    let map_type = TilemapType::default();
+
+    // Each floor is its own tile map stacked in the same spot, only the current one is shown
+    for floor in 0..FLOOR_COUNT {
+        let tilemap_entity = commands.spawn_empty().id();
+        let tile_storage = spawn_tiles(&mut commands, tilemap_entity, floor);
+
+        // Finalize the tile map itself
+        commands.entity(tilemap_entity).insert((
+            TilemapBundle {
+                grid_size,
+                map_type,
+                size: map_size,
+                storage: tile_storage,
+                texture: TilemapTexture::Single(texture_handle.clone()),
+                tile_size,
+                anchor: TilemapAnchor::Center,
+                ..Default::default()
+            },
+            Floor(floor),
+        ));
+
+        if floor == 0 {
+            commands.entity(tilemap_entity).insert(ActiveFloor);
+        }
+    }
+}
+
+/**
+ * Spawn every tile of the map, shared by the windowed and headless runs
+ */
+fn spawn_tiles(commands: &mut Commands, tilemap_entity: Entity, floor: u32) -> TileStorage {
+    let map_size = TilemapSize {
+        x: MAP_SIZE,
+        y: MAP_SIZE,
+    };
 
     let mut tile_storage = TileStorage::empty(map_size);
 
@@ -72,74 +176,408 @@ fn startup(
                         ..Default::default()
                     },
                     TileState::Empty,
+                    TileTerrain::Plains,
                     TileParent(None),
+                    Floor(floor),
                 ))
                 .id();
             tile_storage.set(&tile_pos, tile_entity);
         }
     }
 
-    let tile_size = TilemapTileSize { x: 16.0, y: 16.0 };
-    let grid_size = tile_size.into();
-    let map_type = TilemapType::default();
+    tile_storage
+}
+
+/**
+ * Which floor a tile map, and every tile on it, belongs to
+ */
+#[derive(Debug, Clone, Copy, Component, Default, PartialEq, Eq)]
+struct Floor(u32);
+
+/**
+ * Marks the tile map of the floor being shown and edited
+ */
+#[derive(Debug, Clone, Copy, Component, Default)]
+struct ActiveFloor;
+
+/**
+ * The floor that should be shown, changed by the UI and keyboard
+ */
+#[derive(Debug, Default, Resource)]
+struct CurrentFloor(u32);
+
+/**
+ * Every floor's tile storage, indexed by floor number
+ */
+fn floor_storages<'a>(tilemaps: &'a Query<(&TileStorage, &Floor)>) -> Vec<&'a TileStorage> {
+    let mut storages: Vec<_> = tilemaps.iter().collect();
+    storages.sort_by_key(|(_, floor)| floor.0);
+    storages.into_iter().map(|(storage, _)| storage).collect()
+}
+
+/**
+ * System that moves the ActiveFloor marker and visibility over when the current floor changes
+ */
+fn show_current_floor(
+    mut commands: Commands,
+    current: Res<CurrentFloor>,
+    mut tilemaps: Query<(Entity, &Floor, &mut Visibility)>,
+) {
+    if !current.is_changed() {
+        return;
+    }
 
-    // Finalize the tile map itself
-    commands.entity(tilemap_entity).insert(TilemapBundle {
-        grid_size,
-        map_type,
-        size: map_size,
-        storage: tile_storage,
-        texture: TilemapTexture::Single(texture_handle),
-        tile_size,
-        anchor: TilemapAnchor::Center,
-        ..Default::default()
-    });
+    for (entity, &Floor(floor), mut visibility) in tilemaps.iter_mut() {
+        if floor == current.0 {
+            *visibility = Visibility::Inherited;
+            commands.entity(entity).insert(ActiveFloor);
+        } else {
+            *visibility = Visibility::Hidden;
+            commands.entity(entity).remove::<ActiveFloor>();
+        }
+    }
 }
 
+// Only the parent on the tile's own floor, and only for the latest layer, the pathfinder keeps the full picture
 #[derive(Debug, Clone, Copy, Component, Default, PartialEq, Eq)]
 struct TileParent(Option<TilePos>);
 
 #[derive(Debug, Clone, Copy, Component, Default, PartialEq, Eq)]
-
 enum TileState {
     #[default]
     Empty,
     Wall,
     Start,
     Goal,
+    // Linked to every other portal sharing the same number
+    Portal(u32),
+    // Can only be left in this direction, and not entered against it
+    OneWay(Cardinal),
+    // Picked up by walking over it, opening doors of the same color
+    Key(KeyColor),
+    Door(KeyColor),
+    // Connects to stairs at the same spot on the floors above and below
+    Stairs,
     Queued(u32),
     Visited(u32),
     Final(u32),
 }
 
 /**
- * System that watches a tile's TileState and updates it's sprite color accordingly
+ * The ground underneath a tile, kept separate from TileState so it survives the search overwriting it
  */
-fn color_tile(mut tile_q: Query<(&mut TileColor, &TileState), Changed<TileState>>) {
-    for (mut color, state) in tile_q.iter_mut() {
-        use bevy::color::palettes::basic;
-        color.0 = match state {
-            TileState::Empty => basic::GRAY,
-            TileState::Wall => basic::WHITE,
-            TileState::Start => basic::GREEN,
-            TileState::Goal => basic::RED,
-            TileState::Queued(distance) => {
-                let ratio = *distance as f32 / MAP_SIZE as f32;
-                Color::srgb(ratio, 1.0, 1.0 - ratio)
-                    .mix(&basic::WHITE.into(), 0.70)
-                    .into()
-            }
-            TileState::Visited(distance) => {
-                let ratio = *distance as f32 / MAP_SIZE as f32;
-                Color::srgb(ratio, 1.0, 1.0 - ratio).into()
-            }
-            TileState::Final(distance) => {
-                let ratio = *distance as f32 / MAP_SIZE as f32;
-                Color::srgb(ratio, 1.0, 1.0 - ratio)
-                    .mix(&basic::BLACK.into(), 0.60)
-                    .into()
+#[derive(Debug, Clone, Copy, Component, Default, PartialEq, Eq)]
+enum TileTerrain {
+    #[default]
+    Plains,
+    Forest,
+    Mountains,
+}
+
+impl TileTerrain {
+    /**
+     * How expensive it is to step onto a tile of this terrain
+     */
+    pub fn cost(self) -> u32 {
+        match self {
+            TileTerrain::Plains => 1,
+            TileTerrain::Forest => 3,
+            TileTerrain::Mountains => 6,
+        }
+    }
+}
+
+/**
+ * What color_tile last drew with, so it only has to look at tiles whose state or search record changed since
+ */
+#[derive(Default)]
+struct DrawnColors {
+    mode: Option<ColorMode>,
+    // The search and how far into its touched tiles the colors are caught up to
+    cursor: (usize, usize),
+    // Values are colored as a ratio of this, which only ever doubles so most steps leave the other tiles alone
+    scale: f32,
+}
+
+/**
+ * System that watches a tile's TileState and updates it's sprite color and texture accordingly
+ */
+fn color_tile(
+    overlay: Res<Overlay>,
+    pathfinder: Res<Pathfinder>,
+    mut drawn: Local<DrawnColors>,
+    tilemaps: Query<(&TileStorage, &Floor)>,
+    mut tile_q: Query<(
+        Entity,
+        &mut TileColor,
+        &mut TileTextureIndex,
+        Ref<TileState>,
+        Ref<TileTerrain>,
+        &TilePos,
+        &Floor,
+    )>,
+) {
+    let mode = overlay.color_mode;
+    let value = |pos: TilePos, floor: u32| match mode {
+        ColorMode::Heuristic => None,
+        _ => pathfinder
+            .record(pos, floor)
+            .and_then(|record| mode.value(record)),
+    };
+
+    // Catch up on the records touched since last time, from the beginning if the search started over
+    let searches = pathfinder.searches();
+    let restarted = drawn.cursor.0 != searches;
+    let touched = &pathfinder.touched()[if restarted { 0 } else { drawn.cursor.1 }..];
+    drawn.cursor = (searches, pathfinder.touched().len());
+
+    let mut recolor_all = drawn.mode != Some(mode);
+    drawn.mode = Some(mode);
+    if recolor_all || restarted {
+        drawn.scale = 1.0;
+    }
+    if mode != ColorMode::Heuristic {
+        let max = if recolor_all {
+            pathfinder
+                .records()
+                .filter_map(|record| mode.value(record))
+                .fold(0.0, f32::max)
+        } else {
+            touched
+                .iter()
+                .filter_map(|tile| value(tile.pos, tile.floor))
+                .fold(0.0, f32::max)
+        };
+        while drawn.scale < max {
+            drawn.scale *= 2.0;
+            recolor_all = true;
+        }
+    }
+
+    let storages = floor_storages(&tilemaps);
+    let dirty: HashSet<Entity> = if recolor_all || mode == ColorMode::Heuristic {
+        HashSet::new()
+    } else {
+        touched
+            .iter()
+            .filter_map(|tile| storages.get(tile.floor as usize)?.checked_get(&tile.pos))
+            .collect()
+    };
+
+    for (entity, mut color, mut texture, state, terrain, pos, &Floor(floor)) in tile_q.iter_mut() {
+        if !recolor_all && !state.is_changed() && !terrain.is_changed() && !dirty.contains(&entity)
+        {
+            continue;
+        }
+
+        let value = value(*pos, floor);
+        let new_color = match value {
+            Some(value) if is_search_state(*state) => search_color(*state, value / drawn.scale),
+            // Searched tiles the mode has no number for yet, like queued tiles in expansion order
+            None if mode != ColorMode::Heuristic && is_search_state(*state) => {
+                search_color(*state, 0.0).mix(&bevy::color::palettes::basic::GRAY.into(), 0.8)
             }
+            _ => tile_color(*state, *terrain),
+        };
+        // Only write when it differs, so unchanged tiles don't get sent to the renderer again
+        if color.0 != new_color {
+            color.0 = new_color;
+        }
+
+        // Arrow textures sit after the plain white tile in tiles.png
+        texture.set_if_neq(TileTextureIndex(match *state {
+            TileState::OneWay(Cardinal::North) => 6,
+            TileState::OneWay(Cardinal::East) => 7,
+            TileState::OneWay(Cardinal::South) => 8,
+            TileState::OneWay(Cardinal::West) => 9,
+            _ => 5,
+        }));
+    }
+}
+
+/**
+ * The palette used to draw a tile, shared by the tilemap and image export
+ */
+fn tile_color(state: TileState, terrain: TileTerrain) -> Color {
+    use bevy::color::palettes::basic;
+    match state {
+        TileState::Empty => match terrain {
+            TileTerrain::Plains => basic::GRAY,
+            TileTerrain::Forest => Srgba::rgb(0.25, 0.45, 0.25),
+            TileTerrain::Mountains => Srgba::rgb(0.45, 0.35, 0.25),
+        },
+        TileState::Wall => basic::WHITE,
+        TileState::Start => basic::GREEN,
+        TileState::Goal => basic::RED,
+        TileState::Portal(pair) => portal_color(pair).into(),
+        TileState::OneWay(_) => Srgba::rgb(0.9, 0.6, 0.2),
+        TileState::Key(color) => key_color(color).into(),
+        TileState::Door(color) => key_color(color).mix(&basic::BLACK.into(), 0.5).into(),
+        TileState::Stairs => basic::PURPLE,
+        TileState::Queued(distance) | TileState::Visited(distance) | TileState::Final(distance) => {
+            return search_color(state, distance as f32 / MAP_SIZE as f32);
+        }
+    }
+    .into()
+}
+
+fn is_search_state(state: TileState) -> bool {
+    matches!(
+        state,
+        TileState::Queued(_) | TileState::Visited(_) | TileState::Final(_)
+    )
+}
+
+/**
+ * Tiles the search has reached go from cyan to yellow as the ratio goes from 0 to 1, with queued tiles faded out and
+ * the final path darkened
+ */
+fn search_color(state: TileState, ratio: f32) -> Color {
+    use bevy::color::palettes::basic;
+    let color = Color::srgb(ratio, 1.0, 1.0 - ratio);
+    match state {
+        TileState::Queued(_) => color.mix(&basic::WHITE.into(), 0.70),
+        TileState::Final(_) => color.mix(&basic::BLACK.into(), 0.60),
+        _ => color,
+    }
+}
+
+/**
+ * System that outlines any edges of the map that wrap around, so it's clear why paths leave one side and enter the other
+ */
+fn draw_wrap_edges(
+    mut gizmos: Gizmos,
+    pathfinder: Res<Pathfinder>,
+    tilemap: Single<(&TilemapSize, &TilemapGridSize, &Transform), With<ActiveFloor>>,
+) {
+    let (map_size, grid_size, transform) = *tilemap;
+    let half = Vec2::new(
+        map_size.x as f32 * grid_size.x,
+        map_size.y as f32 * grid_size.y,
+    ) / 2.0;
+    let center = transform.translation.truncate();
+    let color = bevy::color::palettes::basic::YELLOW;
+
+    if pathfinder.topology.wraps_x() {
+        for x in [-half.x, half.x] {
+            gizmos.line_2d(
+                center + Vec2::new(x, -half.y),
+                center + Vec2::new(x, half.y),
+                color,
+            );
         }
-        .into();
+    }
+
+    if pathfinder.topology.wraps_y() {
+        for y in [-half.y, half.y] {
+            gizmos.line_2d(
+                center + Vec2::new(-half.x, y),
+                center + Vec2::new(half.x, y),
+                color,
+            );
+        }
+    }
+}
+
+fn key_color(color: KeyColor) -> Color {
+    use bevy::color::palettes::basic;
+    match color {
+        KeyColor::Yellow => basic::YELLOW,
+        KeyColor::Cyan => basic::AQUA,
+        KeyColor::Magenta => basic::FUCHSIA,
+    }
+    .into()
+}
+
+/**
+ * Each portal pair gets its own color, spread out around the color wheel
+ */
+fn portal_color(pair: u32) -> Color {
+    Color::hsl((pair * 67 % 360) as f32, 0.8, 0.6)
+}
+
+/**
+ * Turn a tile position into the world position of its center
+ */
+fn tile_center(
+    (map_size, grid_size, tile_size, map_type, anchor, transform): (
+        &TilemapSize,
+        &TilemapGridSize,
+        &TilemapTileSize,
+        &TilemapType,
+        &TilemapAnchor,
+        &Transform,
+    ),
+    pos: TilePos,
+) -> Vec2 {
+    let center = pos.center_in_world(map_size, grid_size, tile_size, map_type, anchor);
+    transform.transform_point(center.extend(0.0)).truncate()
+}
+
+/**
+ * System that draws a line between the two ends of every portal pair on the current floor
+ */
+fn draw_portal_links(
+    mut gizmos: Gizmos,
+    pathfinder: Res<Pathfinder>,
+    current: Res<CurrentFloor>,
+    tilemap: Single<
+        (
+            &TilemapSize,
+            &TilemapGridSize,
+            &TilemapTileSize,
+            &TilemapType,
+            &TilemapAnchor,
+            &Transform,
+        ),
+        With<ActiveFloor>,
+    >,
+) {
+    for (pair, a, b) in pathfinder.portal_links() {
+        if a.floor != current.0 || b.floor != current.0 {
+            continue;
+        }
+
+        gizmos.line_2d(
+            tile_center(*tilemap, a.pos),
+            tile_center(*tilemap, b.pos),
+            portal_color(pair),
+        );
+    }
+}
+
+/**
+ * System that circles the spots where the found path leaves the current floor, green going up and red going down
+ */
+fn draw_floor_changes(
+    mut gizmos: Gizmos,
+    pathfinder: Res<Pathfinder>,
+    current: Res<CurrentFloor>,
+    tilemap: Single<
+        (
+            &TilemapSize,
+            &TilemapGridSize,
+            &TilemapTileSize,
+            &TilemapType,
+            &TilemapAnchor,
+            &Transform,
+        ),
+        With<ActiveFloor>,
+    >,
+) {
+    use bevy::color::palettes::basic;
+
+    for pair in pathfinder.path.windows(2) {
+        let (from, to) = (pair[0], pair[1]);
+        if from.floor == to.floor || from.floor != current.0 {
+            continue;
+        }
+
+        let color = if to.floor > from.floor {
+            basic::LIME
+        } else {
+            basic::RED
+        };
+        gizmos.circle_2d(tile_center(*tilemap, from.pos), 6.0, color);
     }
 }
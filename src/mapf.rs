@@ -0,0 +1,735 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet, VecDeque},
+};
+
+use bevy::{color::palettes::basic, prelude::*};
+use bevy_ecs_tilemap::prelude::*;
+
+use crate::{ActiveFloor, CurrentFloor, MAP_SIZE, TileState, smoothing::blocks_sight, tile_center};
+
+pub fn mapf_plugin(app: &mut App) {
+    app.init_resource::<Mapf>()
+        .add_systems(Update, (animate_agents, draw_agents).chain());
+}
+
+// Steps an agent's plan may run past its shortest path, for each agent it might have to make way for
+const SLACK_PER_AGENT: u32 = MAP_SIZE;
+
+// Conflict-based search gives up after splitting this many times
+const MAX_CBS_NODES: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MapfSolver {
+    #[default]
+    Cooperative,
+    Windowed,
+    ConflictBased,
+}
+
+/**
+ * Multi-agent pathfinding, every start is paired with a goal and no two agents may share a tile or swap places
+ */
+#[derive(Debug, Resource)]
+pub struct Mapf {
+    pub solver: MapfSolver,
+    // How many steps ahead WHCA* reserves before replanning
+    pub window: u32,
+    pub playing: bool,
+    pub speed: f32,
+
+    solution: Option<MapfSolution>,
+    // Time along the paths being shown, in steps
+    clock: f32,
+}
+
+impl Default for Mapf {
+    fn default() -> Self {
+        Self {
+            solver: MapfSolver::default(),
+            window: 8,
+            playing: true,
+            speed: 4.0,
+
+            solution: None,
+            clock: 0.0,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct MapfSolution {
+    floor: u32,
+    // Where each agent is at every step, agents stay on their goal after their path ends
+    paths: Vec<Vec<TilePos>>,
+    // Conflicts the agents would have run into if each had planned alone
+    pub resolved: Vec<Conflict>,
+    // Starts or goals left over once the other kind ran out, which sit out the plan
+    pub unpaired: usize,
+}
+
+impl MapfSolution {
+    pub fn agent_count(&self) -> usize {
+        self.paths.len()
+    }
+
+    pub fn sum_of_costs(&self) -> usize {
+        self.paths.iter().map(|path| path.len() - 1).sum()
+    }
+
+    pub fn makespan(&self) -> usize {
+        self.paths
+            .iter()
+            .map(|path| path.len() - 1)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/**
+ * Two agents on the same tile at the same time, or swapping tiles between `time - 1` and `time`
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Conflict {
+    agents: (usize, usize),
+    pos: TilePos,
+    time: u32,
+    // For swaps, the tile the first agent came from, which the second agent moved to
+    swap: Option<TilePos>,
+}
+
+/**
+ * A start and goal pair, with how far every tile that can reach the goal is from it when nobody else is around
+ */
+#[derive(Debug)]
+struct Agent {
+    start: TilePos,
+    goal: TilePos,
+    distances: HashMap<TilePos, u32>,
+}
+
+impl Agent {
+    /**
+     * Breadth first out from the goal over walkable tiles, returns None if the start can't be reached at all
+     */
+    fn new(start: TilePos, goal: TilePos, walkable: &impl Fn(TilePos) -> bool) -> Option<Self> {
+        let mut distances = HashMap::from([(goal, 0)]);
+        let mut queue = VecDeque::from([goal]);
+        while let Some(pos) = queue.pop_front() {
+            let distance = distances[&pos];
+            for next in moves(pos) {
+                if walkable(next) && !distances.contains_key(&next) {
+                    distances.insert(next, distance + 1);
+                    queue.push_back(next);
+                }
+            }
+        }
+
+        distances.contains_key(&start).then_some(Self {
+            start,
+            goal,
+            distances,
+        })
+    }
+}
+
+/**
+ * The tiles one step away inside the map
+ */
+fn moves(pos: TilePos) -> impl Iterator<Item = TilePos> {
+    [(1, 0), (-1, 0), (0, 1), (0, -1)]
+        .into_iter()
+        .filter_map(move |(dx, dy)| {
+            let (x, y) = (pos.x as i32 + dx, pos.y as i32 + dy);
+            ((0..MAP_SIZE as i32).contains(&x) && (0..MAP_SIZE as i32).contains(&y))
+                .then(|| TilePos::new(x as u32, y as u32))
+        })
+}
+
+/**
+ * A rule added by conflict-based search, the agent can't be at `pos` at `time`, or with `from` can't move from there
+ */
+#[derive(Debug, Clone, Copy)]
+struct Constraint {
+    agent: usize,
+    pos: TilePos,
+    time: u32,
+    from: Option<TilePos>,
+}
+
+impl Mapf {
+    pub fn solution(&self) -> Option<&MapfSolution> {
+        self.solution.as_ref()
+    }
+
+    pub fn clear(&mut self) {
+        self.solution = None;
+        self.clock = 0.0;
+    }
+
+    /**
+     * Pair up starts and goals on the current floor in reading order and plan for all of them,
+     * returns false if there are no pairs or the solver couldn't find a way for everyone
+     */
+    pub fn solve(
+        &mut self,
+        tile_states: Query<&TileState>,
+        storage: &TileStorage,
+        floor: u32,
+    ) -> bool {
+        self.clear();
+
+        let state = |pos: TilePos| *tile_states.get(storage.checked_get(&pos).unwrap()).unwrap();
+        let walkable = |pos: TilePos| !blocks_sight(state(pos));
+
+        let positions = (0..MAP_SIZE)
+            .rev()
+            .flat_map(|y| (0..MAP_SIZE).map(move |x| TilePos::new(x, y)));
+        let starts: Vec<TilePos> = positions
+            .clone()
+            .filter(|&pos| state(pos) == TileState::Start)
+            .collect();
+        let goals: Vec<TilePos> = positions
+            .filter(|&pos| state(pos) == TileState::Goal)
+            .collect();
+        let unpaired = starts.len().abs_diff(goals.len());
+        if unpaired > 0 {
+            warn!(
+                "mapf has {} starts and {} goals, {} will be left out",
+                starts.len(),
+                goals.len(),
+                unpaired
+            );
+        }
+        let pairs: Vec<(TilePos, TilePos)> = starts.into_iter().zip(goals).collect();
+
+        if pairs.is_empty() {
+            debug!("mapf needs at least one start and goal");
+            return false;
+        }
+
+        let Some((paths, resolved)) = plan(self.solver, self.window, &pairs, &walkable) else {
+            debug!("mapf found no solution for {} agents", pairs.len());
+            return false;
+        };
+
+        let solution = MapfSolution {
+            floor,
+            paths,
+            resolved,
+            unpaired,
+        };
+        debug!(
+            "mapf solved {} agents with sum of costs {} and makespan {}, resolving {} conflicts",
+            solution.agent_count(),
+            solution.sum_of_costs(),
+            solution.makespan(),
+            solution.resolved.len()
+        );
+        self.solution = Some(solution);
+        true
+    }
+}
+
+/**
+ * Plan for every start and goal pair, along with the conflicts they'd have run into planning alone
+ * Fails straight away if any goal can't be reached even with nobody else around
+ */
+fn plan(
+    solver: MapfSolver,
+    window: u32,
+    pairs: &[(TilePos, TilePos)],
+    walkable: &impl Fn(TilePos) -> bool,
+) -> Option<(Vec<Vec<TilePos>>, Vec<Conflict>)> {
+    let mut agents = Vec::new();
+    for (index, &(start, goal)) in pairs.iter().enumerate() {
+        let Some(agent) = Agent::new(start, goal, walkable) else {
+            debug!("mapf agent {} can't reach its goal", index);
+            return None;
+        };
+        agents.push(agent);
+    }
+    let slack = SLACK_PER_AGENT * agents.len() as u32;
+
+    let paths = match solver {
+        MapfSolver::Cooperative => cooperative(&agents, slack),
+        MapfSolver::Windowed => windowed(&agents, slack, window.max(2)),
+        MapfSolver::ConflictBased => conflict_based(&agents, slack),
+    }?;
+
+    // Planning alone shows which conflicts the solver had to work around
+    let alone: Option<Vec<_>> = agents
+        .iter()
+        .map(|agent| space_time_search(agent, agent.start, slack, |_, _, _| false, 0))
+        .collect();
+    let resolved = alone.map_or_else(Vec::new, |alone| find_conflicts(&alone));
+
+    Some((paths, resolved))
+}
+
+/**
+ * Where an agent is at a time, staying put once its path runs out
+ */
+fn at(path: &[TilePos], time: u32) -> TilePos {
+    path[(time as usize).min(path.len() - 1)]
+}
+
+/**
+ * A* over position and time from wherever the agent is now, it can also wait in place
+ * `blocked` gets the move being made and the time it arrives, and the goal only counts once nothing is blocked there
+ * from `goal_free_after` on, so the agent can stay
+ * Only tiles that can reach the goal are tried, and it gives up after `slack` more steps than the way there alone
+ */
+fn space_time_search(
+    agent: &Agent,
+    start: TilePos,
+    slack: u32,
+    blocked: impl Fn(TilePos, TilePos, u32) -> bool,
+    goal_free_after: u32,
+) -> Option<Vec<TilePos>> {
+    let goal = agent.goal;
+    let limit = (*agent.distances.get(&start)?).max(goal_free_after) + slack;
+    let heuristic = |pos: TilePos| agent.distances[&pos];
+
+    let mut queue = BinaryHeap::from([Reverse((heuristic(start), 0, start.x, start.y))]);
+    let mut parents: HashMap<(TilePos, u32), TilePos> = HashMap::new();
+    let mut closed = HashSet::new();
+
+    while let Some(Reverse((_, time, x, y))) = queue.pop() {
+        let pos = TilePos::new(x, y);
+        if !closed.insert((pos, time)) {
+            continue;
+        }
+
+        if pos == goal && time >= goal_free_after {
+            let mut path = vec![pos];
+            let mut current = (pos, time);
+            while let Some(&parent) = parents.get(&current) {
+                path.push(parent);
+                current = (parent, current.1 - 1);
+            }
+            path.reverse();
+            return Some(path);
+        }
+
+        if time >= limit {
+            continue;
+        }
+
+        for next in std::iter::once(pos).chain(moves(pos)) {
+            let arrival = time + 1;
+            if !agent.distances.contains_key(&next)
+                || blocked(pos, next, arrival)
+                || closed.contains(&(next, arrival))
+            {
+                continue;
+            }
+
+            parents.entry((next, arrival)).or_insert(pos);
+            queue.push(Reverse((
+                arrival + heuristic(next),
+                arrival,
+                next.x,
+                next.y,
+            )));
+        }
+    }
+
+    None
+}
+
+/**
+ * Every vertex and swap conflict between pairs of agents, in order of time
+ */
+fn find_conflicts(paths: &[Vec<TilePos>]) -> Vec<Conflict> {
+    let makespan = paths.iter().map(|path| path.len()).max().unwrap_or(0) as u32;
+    let mut conflicts = Vec::new();
+
+    for time in 1..makespan {
+        for a in 0..paths.len() {
+            for b in (a + 1)..paths.len() {
+                let (a_pos, b_pos) = (at(&paths[a], time), at(&paths[b], time));
+                let (a_prev, b_prev) = (at(&paths[a], time - 1), at(&paths[b], time - 1));
+
+                if a_pos == b_pos {
+                    conflicts.push(Conflict {
+                        agents: (a, b),
+                        pos: a_pos,
+                        time,
+                        swap: None,
+                    });
+                } else if a_pos == b_prev && b_pos == a_prev {
+                    conflicts.push(Conflict {
+                        agents: (a, b),
+                        pos: a_pos,
+                        time,
+                        swap: Some(a_prev),
+                    });
+                }
+            }
+        }
+    }
+
+    conflicts
+}
+
+/**
+ * Tiles and moves claimed by agents that have already planned
+ */
+#[derive(Debug, Default)]
+struct Reservations {
+    tiles: HashSet<(TilePos, u32)>,
+    moves: HashSet<(TilePos, TilePos, u32)>,
+    // Agents that have finished stay on their goal from this time on
+    parked: HashMap<TilePos, u32>,
+    // Latest time each tile is claimed, so an agent knows when it can settle on its goal
+    latest: HashMap<TilePos, u32>,
+}
+
+impl Reservations {
+    /**
+     * Claim a path, only its first `limit` steps if given, otherwise all of it and its goal forever after
+     */
+    fn reserve(&mut self, path: &[TilePos], limit: Option<u32>) {
+        let end = limit.map_or(path.len() as u32, |limit| {
+            (limit + 1).min(path.len() as u32)
+        });
+        for time in 0..end {
+            let pos = path[time as usize];
+            self.tiles.insert((pos, time));
+            let latest = self.latest.entry(pos).or_default();
+            *latest = (*latest).max(time);
+
+            if time > 0 {
+                self.moves.insert((path[time as usize - 1], pos, time));
+            }
+        }
+
+        let goal = *path.last().unwrap();
+        match limit {
+            None => {
+                self.parked.insert(goal, path.len() as u32 - 1);
+            }
+            // Inside the window an agent that's already done still holds its spot
+            Some(limit) => {
+                for time in path.len() as u32..=limit {
+                    self.tiles.insert((goal, time));
+                }
+            }
+        }
+    }
+
+    fn blocked(&self, from: TilePos, to: TilePos, time: u32) -> bool {
+        self.tiles.contains(&(to, time))
+            || self.moves.contains(&(to, from, time))
+            || self.parked.get(&to).is_some_and(|&parked| parked <= time)
+    }
+
+    fn goal_free_after(&self, goal: TilePos) -> u32 {
+        self.latest.get(&goal).map_or(0, |&latest| latest + 1)
+    }
+}
+
+/**
+ * Cooperative A*, agents plan one at a time in order, each avoiding everything claimed before it
+ */
+fn cooperative(agents: &[Agent], slack: u32) -> Option<Vec<Vec<TilePos>>> {
+    let mut reservations = Reservations::default();
+    let mut paths = Vec::new();
+
+    for agent in agents {
+        let path = space_time_search(
+            agent,
+            agent.start,
+            slack,
+            |from, to, time| reservations.blocked(from, to, time),
+            reservations.goal_free_after(agent.goal),
+        )?;
+        reservations.reserve(&path, None);
+        paths.push(path);
+    }
+
+    Some(paths)
+}
+
+/**
+ * Windowed Hierarchical Cooperative A*, agents only reserve a window ahead and replan halfway through it,
+ * taking turns going first so nobody is stuck at the back
+ */
+fn windowed(agents: &[Agent], slack: u32, window: u32) -> Option<Vec<Vec<TilePos>>> {
+    let mut paths: Vec<Vec<TilePos>> = agents.iter().map(|agent| vec![agent.start]).collect();
+    let advance = window / 2;
+
+    // Everyone should be home by the time the farthest agent could have walked there with the slack to spare
+    let horizon = agents
+        .iter()
+        .map(|agent| agent.distances[&agent.start])
+        .max()
+        .unwrap_or(0)
+        + slack;
+
+    for round in 0..horizon.div_ceil(advance) as usize {
+        if paths
+            .iter()
+            .zip(agents)
+            .all(|(path, agent)| *path.last().unwrap() == agent.goal)
+        {
+            break;
+        }
+
+        let mut reservations = Reservations::default();
+        let mut plans = vec![Vec::new(); agents.len()];
+
+        for turn in 0..agents.len() {
+            let agent = (round + turn) % agents.len();
+            let current = *paths[agent].last().unwrap();
+            let goal = agents[agent].goal;
+
+            // Reservations only matter inside the window, past it the plan is just a rough direction
+            // This only fails when boxed in, in which case waiting is the best bet
+            let plan = space_time_search(
+                &agents[agent],
+                current,
+                slack,
+                |from, to, time| time <= window && reservations.blocked(from, to, time),
+                reservations.goal_free_after(goal),
+            )
+            .unwrap_or_else(|| vec![current]);
+
+            reservations.reserve(&plan, Some(window));
+            plans[agent] = plan;
+        }
+
+        for (path, plan) in paths.iter_mut().zip(plans) {
+            path.extend((1..=advance).map(|time| at(&plan, time)));
+        }
+    }
+
+    // Trim the waiting at the end of each path once the agent is home for good
+    for (path, agent) in paths.iter_mut().zip(agents) {
+        let goal = agent.goal;
+        if *path.last().unwrap() != goal {
+            return None;
+        }
+        while path.len() > 1 && path[path.len() - 2] == goal {
+            path.pop();
+        }
+    }
+
+    Some(paths)
+}
+
+/**
+ * Conflict-based search, plans every agent alone and then splits on the first conflict found,
+ * trying again with either agent forbidden from it, cheapest total first
+ */
+fn conflict_based(agents: &[Agent], slack: u32) -> Option<Vec<Vec<TilePos>>> {
+    let plan = |agent: usize, constraints: &[Constraint]| {
+        let goal = agents[agent].goal;
+        let mine = || constraints.iter().filter(move |c| c.agent == agent);
+        let goal_free_after = mine()
+            .filter(|c| c.pos == goal && c.from.is_none())
+            .map(|c| c.time + 1)
+            .max()
+            .unwrap_or(0);
+
+        space_time_search(
+            &agents[agent],
+            agents[agent].start,
+            slack,
+            |from, to, time| {
+                mine().any(|c| c.time == time && c.pos == to && c.from.is_none_or(|f| f == from))
+            },
+            goal_free_after,
+        )
+    };
+    let cost = |paths: &[Vec<TilePos>]| paths.iter().map(|path| path.len()).sum::<usize>();
+
+    let root: Option<Vec<_>> = (0..agents.len()).map(|agent| plan(agent, &[])).collect();
+    let root = root?;
+
+    let mut nodes = vec![(Vec::<Constraint>::new(), root)];
+    let mut queue = BinaryHeap::from([Reverse((cost(&nodes[0].1), 0))]);
+
+    while let Some(Reverse((_, index))) = queue.pop() {
+        if nodes.len() > MAX_CBS_NODES {
+            debug!("cbs gave up after {} nodes", nodes.len());
+            return None;
+        }
+
+        let (constraints, paths) = nodes[index].clone();
+        let Some(&conflict) = find_conflicts(&paths).first() else {
+            return Some(paths);
+        };
+
+        let (a, b) = conflict.agents;
+        let split = match conflict.swap {
+            None => [(a, conflict.pos, None), (b, conflict.pos, None)],
+            Some(a_from) => [
+                (a, conflict.pos, Some(a_from)),
+                (b, a_from, Some(conflict.pos)),
+            ],
+        };
+
+        for (agent, pos, from) in split {
+            let mut constraints = constraints.clone();
+            constraints.push(Constraint {
+                agent,
+                pos,
+                time: conflict.time,
+                from,
+            });
+
+            let Some(path) = plan(agent, &constraints) else {
+                continue;
+            };
+            let mut paths = paths.clone();
+            paths[agent] = path;
+
+            queue.push(Reverse((cost(&paths), nodes.len())));
+            nodes.push((constraints, paths));
+        }
+    }
+
+    None
+}
+
+fn agent_color(agent: usize) -> Color {
+    Color::hsl((agent * 137 % 360) as f32, 0.9, 0.5)
+}
+
+/**
+ * System that plays the solution back, looping with a short pause at the end
+ */
+fn animate_agents(time: Res<Time>, mut mapf: ResMut<Mapf>) {
+    let Some(makespan) = mapf.solution.as_ref().map(|solution| solution.makespan()) else {
+        return;
+    };
+
+    if mapf.playing {
+        mapf.clock += time.delta_secs() * mapf.speed;
+        if mapf.clock > makespan as f32 + 2.0 {
+            mapf.clock = 0.0;
+        }
+    }
+}
+
+/**
+ * System that draws each agent's path and where it is right now, with resolved conflicts circled
+ */
+fn draw_agents(
+    mut gizmos: Gizmos,
+    mapf: Res<Mapf>,
+    current: Res<CurrentFloor>,
+    tilemap: Single<
+        (
+            &TilemapSize,
+            &TilemapGridSize,
+            &TilemapTileSize,
+            &TilemapType,
+            &TilemapAnchor,
+            &Transform,
+        ),
+        With<ActiveFloor>,
+    >,
+) {
+    let Some(solution) = &mapf.solution else {
+        return;
+    };
+    if solution.floor != current.0 {
+        return;
+    }
+
+    let (_, grid_size, ..) = *tilemap;
+    let step = mapf.clock.floor() as u32;
+    let progress = mapf.clock.fract();
+
+    for (agent, path) in solution.paths.iter().enumerate() {
+        let color = agent_color(agent);
+
+        gizmos.linestrip_2d(
+            path.iter().map(|&pos| tile_center(*tilemap, pos)),
+            color.with_alpha(0.4),
+        );
+
+        let position = tile_center(*tilemap, at(path, step))
+            .lerp(tile_center(*tilemap, at(path, step + 1)), progress);
+        gizmos.circle_2d(position, grid_size.x * 0.35, color);
+    }
+
+    for conflict in &solution.resolved {
+        // Light up each conflict as the moment it would have happened goes by
+        let color = if conflict.time == step {
+            basic::RED
+        } else {
+            basic::MAROON
+        };
+
+        let center = match conflict.swap {
+            Some(from) => {
+                tile_center(*tilemap, conflict.pos).lerp(tile_center(*tilemap, from), 0.5)
+            }
+            None => tile_center(*tilemap, conflict.pos),
+        };
+        gizmos.circle_2d(center, grid_size.x * 0.5, color);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOLVERS: [MapfSolver; 3] = [
+        MapfSolver::Cooperative,
+        MapfSolver::Windowed,
+        MapfSolver::ConflictBased,
+    ];
+
+    #[test]
+    fn solutions_are_conflict_free() {
+        // Two agents meeting head on and one crossing their row, in a small open room
+        let walkable = |pos: TilePos| pos.x < 5 && pos.y < 5;
+        let pairs = [
+            (TilePos::new(0, 2), TilePos::new(4, 2)),
+            (TilePos::new(4, 2), TilePos::new(0, 2)),
+            (TilePos::new(2, 0), TilePos::new(2, 4)),
+        ];
+
+        for solver in SOLVERS {
+            let (paths, _) = plan(solver, 4, &pairs, &walkable).unwrap();
+
+            assert!(find_conflicts(&paths).is_empty(), "{:?} collided", solver);
+            for (path, &(start, goal)) in paths.iter().zip(&pairs) {
+                assert_eq!(path.first(), Some(&start));
+                assert_eq!(path.last(), Some(&goal));
+                for step in path.windows(2) {
+                    assert!(step[0] == step[1] || moves(step[0]).any(|next| next == step[1]));
+                    assert!(walkable(step[1]));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unreachable_goal_fails() {
+        // A wall down the middle splits the map in two
+        let walkable = |pos: TilePos| pos.x != MAP_SIZE / 2;
+        let pairs = [(TilePos::new(0, 0), TilePos::new(MAP_SIZE - 1, 0))];
+
+        for solver in SOLVERS {
+            assert!(plan(solver, 8, &pairs, &walkable).is_none());
+        }
+    }
+
+    #[test]
+    fn goal_behind_parked_agent_fails() {
+        // A corridor one tile wide, the first agent is already home and sits in the way forever
+        let walkable = |pos: TilePos| pos.y == 0;
+        let pairs = [
+            (TilePos::new(1, 0), TilePos::new(1, 0)),
+            (TilePos::new(0, 0), TilePos::new(5, 0)),
+        ];
+
+        assert!(plan(MapfSolver::Cooperative, 8, &pairs, &walkable).is_none());
+    }
+}
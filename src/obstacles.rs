@@ -0,0 +1,147 @@
+use bevy::prelude::*;
+use bevy_ecs_tilemap::prelude::*;
+use rand::{rng, seq::IndexedRandom};
+
+use crate::{Floor, MAP_SIZE, TileState, floor_storages, pursuit::Pursuit};
+
+pub fn obstacles_plugin(app: &mut App) {
+    app.init_resource::<Obstacles>()
+        .add_systems(FixedUpdate, toggle_obstacles);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ObstacleMode {
+    #[default]
+    Off,
+    // Walls pop up on any open tile of the floor
+    Random,
+    // Walls drop onto the chaser's path a few tiles ahead of it
+    Ambush,
+}
+
+/**
+ * Walls that come and go while the pursuit runs, forcing the chaser to notice and replan around them
+ */
+#[derive(Debug, Resource)]
+pub struct Obstacles {
+    pub mode: ObstacleMode,
+    // Fixed update ticks between each new wall, and how long each one stays
+    pub every: usize,
+    pub lifetime: usize,
+    // How many tiles ahead of the chaser ambushes land
+    pub lead: usize,
+
+    tick: usize,
+    // Walls that were placed, with the floor they're on and the tick they go away on
+    placed: Vec<(u32, TilePos, usize)>,
+}
+
+impl Default for Obstacles {
+    fn default() -> Self {
+        Self {
+            mode: ObstacleMode::default(),
+            every: 24,
+            lifetime: 96,
+            lead: 3,
+
+            tick: 0,
+            placed: Vec::new(),
+        }
+    }
+}
+
+impl Obstacles {
+    pub fn active(&self) -> usize {
+        self.placed.len()
+    }
+
+    /**
+     * Pick where the next wall goes, if anywhere fits
+     */
+    fn pick(
+        &self,
+        pursuit: &Pursuit,
+        tile_states: &Query<&mut TileState>,
+        storage: &TileStorage,
+    ) -> Option<TilePos> {
+        let open = |pos: TilePos| {
+            matches!(
+                *tile_states.get(storage.checked_get(&pos).unwrap()).unwrap(),
+                TileState::Empty
+                    | TileState::Queued(_)
+                    | TileState::Visited(_)
+                    | TileState::Final(_)
+            )
+        };
+
+        match self.mode {
+            ObstacleMode::Off => None,
+            ObstacleMode::Random => (0..MAP_SIZE)
+                .flat_map(|y| (0..MAP_SIZE).map(move |x| TilePos::new(x, y)))
+                .filter(|&pos| open(pos))
+                .collect::<Vec<_>>()
+                .choose(&mut rng())
+                .copied(),
+            ObstacleMode::Ambush => {
+                // Never on the chaser or on the goal it's heading for
+                let path = pursuit.path();
+                let index = self.lead.max(1).min(path.len().saturating_sub(2));
+                (index >= 1).then(|| path[index]).filter(|&pos| open(pos))
+            }
+        }
+    }
+}
+
+/**
+ * System that places and clears walls on a schedule while the pursuit runs, and takes all of them away once it stops
+ */
+fn toggle_obstacles(
+    mut obstacles: ResMut<Obstacles>,
+    pursuit: Res<Pursuit>,
+    mut tile_states: Query<&mut TileState>,
+    tilemaps: Query<(&TileStorage, &Floor)>,
+) {
+    let storages = floor_storages(&tilemaps);
+    let clear = |tile_states: &mut Query<&mut TileState>, floor: u32, pos: TilePos| {
+        let mut state = tile_states
+            .get_mut(storages[floor as usize].checked_get(&pos).unwrap())
+            .unwrap();
+        // Leave it alone if it's been painted over since
+        if *state == TileState::Wall {
+            *state = TileState::Empty;
+        }
+    };
+
+    if !pursuit.running() {
+        for (floor, pos, _) in std::mem::take(&mut obstacles.placed) {
+            clear(&mut tile_states, floor, pos);
+        }
+        obstacles.tick = 0;
+        return;
+    }
+    obstacles.tick += 1;
+    let tick = obstacles.tick;
+
+    let (expired, placed): (Vec<_>, Vec<_>) = std::mem::take(&mut obstacles.placed)
+        .into_iter()
+        .partition(|&(_, _, until)| until <= tick);
+    obstacles.placed = placed;
+    for (floor, pos, _) in expired {
+        clear(&mut tile_states, floor, pos);
+    }
+
+    if !tick.is_multiple_of(obstacles.every.max(1)) {
+        return;
+    }
+
+    let floor = pursuit.floor();
+    let storage = storages[floor as usize];
+    if let Some(pos) = obstacles.pick(&pursuit, &tile_states, storage) {
+        debug!("dropped an obstacle on {:?} floor {}", pos, floor);
+        *tile_states
+            .get_mut(storage.checked_get(&pos).unwrap())
+            .unwrap() = TileState::Wall;
+        let until = tick + obstacles.lifetime;
+        obstacles.placed.push((floor, pos, until));
+    }
+}
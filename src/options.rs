@@ -1,15 +1,34 @@
-use bevy::prelude::*;
-use bevy_ecs_tilemap::tiles::{TilePos, TileStorage};
+use bevy::{ecs::system::SystemParam, prelude::*};
+use bevy_ecs_tilemap::tiles::TileStorage;
 use bevy_egui::{
     EguiContexts, EguiPrimaryContextPass,
-    egui::{Align, Grid, Layout, RichText, Slider, Window},
+    egui::{Align, Button, Color32, Grid, Layout, RichText, Slider, Window},
 };
 
 use crate::{
-    TileParent, TileState,
+    ActiveFloor, CurrentFloor, FLOOR_COUNT, Floor, TileParent, TileState, TileTerrain,
     algorithm::AlgorithmOption,
-    generate::{flush_path, generate_flat, generate_maze, generate_noise},
+    brush::{Brush, BrushTool, PAINTS},
+    export::{ExportOverlays, export_png, import_png},
+    floor_storages,
+    flow_field::FlowField,
+    frontier::FrontierViewer,
+    generate::{
+        FractalOption, NoiseSettings, WfcSample, flush_path, generate_flat, generate_maze,
+        generate_noise, generate_wfc,
+    },
+    history::{History, stop_search},
+    hpa::Hpa,
+    inspector::Inspector,
+    mapf::{Mapf, MapfSolver},
+    obstacles::{ObstacleMode, Obstacles},
+    overlay::{ColorMode, Overlay},
     pathfinder::Pathfinder,
+    pathfinder_tile::Topology,
+    pursuit::{GoalMovement, Pursuit, Replanning},
+    recorder::Recorder,
+    smoothing::SmoothedPath,
+    walker::Walker,
 };
 
 pub fn options_plugin(app: &mut App) {
@@ -30,8 +49,16 @@ struct Options {
     current_tick: usize,
 
     // Map generation options
-    noise_scale: f64,
-    noise_threshold: f64,
+    noise: NoiseSettings,
+    wfc_sample: WfcSample,
+    wfc_sample_size: u32,
+    wfc_sample_path: String,
+
+    // Image export options
+    image_path: String,
+    gif_path: String,
+    trace_path: String,
+    image_overlays: ExportOverlays,
 }
 
 impl Default for Options {
@@ -43,26 +70,73 @@ impl Default for Options {
             auto_speed: 20,
             current_tick: 0,
 
-            noise_scale: 5.5,
-            noise_threshold: 0.0,
+            noise: NoiseSettings::default(),
+            wfc_sample: WfcSample::default(),
+            wfc_sample_size: 8,
+            wfc_sample_path: "sample.txt".to_string(),
+
+            image_path: "map.png".to_string(),
+            gif_path: "run.gif".to_string(),
+            trace_path: "trace.json".to_string(),
+            image_overlays: ExportOverlays::default(),
         }
     }
 }
 
+/**
+ * Resources of the tools that work alongside the pathfinder, bundled to keep the menu under the system parameter limit
+ */
+#[derive(SystemParam)]
+struct Tools<'w> {
+    smoothed: ResMut<'w, SmoothedPath>,
+    hpa: ResMut<'w, Hpa>,
+    flow_field: ResMut<'w, FlowField>,
+    mapf: ResMut<'w, Mapf>,
+    walker: ResMut<'w, Walker>,
+    pursuit: ResMut<'w, Pursuit>,
+    obstacles: ResMut<'w, Obstacles>,
+    brush: ResMut<'w, Brush>,
+    history: ResMut<'w, History>,
+    inspector: ResMut<'w, Inspector>,
+    overlay: ResMut<'w, Overlay>,
+    frontier: ResMut<'w, FrontierViewer>,
+}
+
 fn options_menu(
     mut contexts: EguiContexts,
     mut pathfinder: ResMut<Pathfinder>,
     mut options: ResMut<Options>,
+    mut recorder: ResMut<Recorder>,
+    tools: Tools,
     mut tile_states: Query<&mut TileState>,
+    mut tile_terrains: Query<&mut TileTerrain>,
     mut tile_parents: Query<&mut TileParent>,
-    mut tiles_positions: Query<&TilePos>,
-    storage: Single<&TileStorage>,
+    mut current_floor: ResMut<CurrentFloor>,
+    storage: Single<&TileStorage, With<ActiveFloor>>,
+    tilemaps: Query<(&TileStorage, &Floor)>,
 ) {
+    let Tools {
+        mut smoothed,
+        mut hpa,
+        mut flow_field,
+        mut mapf,
+        mut walker,
+        mut pursuit,
+        mut obstacles,
+        mut brush,
+        mut history,
+        mut inspector,
+        mut overlay,
+        mut frontier,
+    } = tools;
     let spacing = 10.0;
 
     Window::new("Options").show(contexts.ctx_mut().unwrap(), |ui| {
         ui.add_space(spacing);
-        ui.heading(format!("Algorithm (step {})", pathfinder.step));
+        ui.heading(format!(
+            "Algorithm (step {}, keys {:03b})",
+            pathfinder.step, pathfinder.layer
+        ));
         ui.separator();
         {
             let mut restart = false;
@@ -82,14 +156,26 @@ fn options_menu(
                     .radio_value(
                         &mut options.algorithm,
                         AlgorithmOption::BreadthFirst,
-                        "Dijkstra",
+                        "Breadth First",
                     )
                     .changed();
-                ui.label("Breadth First");
+                ui.label("Flood");
                 ui.label("Queue");
                 ui.label("Oldest");
                 ui.end_row();
 
+                restart |= ui
+                    .radio_value(
+                        &mut options.algorithm,
+                        AlgorithmOption::Dijkstra,
+                        "Dijkstra",
+                    )
+                    .changed();
+                ui.label("Uniform Cost");
+                ui.label("Binary Heap");
+                ui.label("Cheapest");
+                ui.end_row();
+
                 restart |= ui
                     .radio_value(&mut options.algorithm, AlgorithmOption::AStar, "A*")
                     .changed();
@@ -129,11 +215,51 @@ fn options_menu(
                 ui.label("Array");
                 ui.label("Random");
                 ui.end_row();
+
+                restart |= ui
+                    .radio_value(&mut options.algorithm, AlgorithmOption::ThetaStar, "Theta*")
+                    .changed();
+                ui.label("Any Angle");
+                ui.label("Binary Heap");
+                ui.label("Cheapest");
+                ui.end_row();
+
+                restart |= ui
+                    .radio_value(
+                        &mut options.algorithm,
+                        AlgorithmOption::LazyThetaStar,
+                        "Lazy Theta*",
+                    )
+                    .changed();
+                ui.label("Any Angle");
+                ui.label("Binary Heap");
+                ui.label("Cheapest");
+                ui.end_row();
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Topology");
+                for (topology, label) in [
+                    (Topology::Bounded, "Bounded"),
+                    (Topology::WrapHorizontal, "Wrap X"),
+                    (Topology::WrapVertical, "Wrap Y"),
+                    (Topology::Torus, "Torus"),
+                ] {
+                    // Writing through bypass keeps the pathfinder from looking changed every frame the menu is up
+                    restart |= ui
+                        .radio_value(
+                            &mut pathfinder.bypass_change_detection().topology,
+                            topology,
+                            label,
+                        )
+                        .changed();
+                }
             });
 
             if restart {
                 pathfinder.restart(options.algorithm);
                 flush_path(tile_states.reborrow(), tile_parents.reborrow());
+                recorder.clear();
             }
         }
 
@@ -151,64 +277,533 @@ fn options_menu(
             if ui.button("Restart").clicked() {
                 pathfinder.restart(options.algorithm);
                 flush_path(tile_states.reborrow(), tile_parents.reborrow());
+                recorder.clear();
             };
 
             if ui.button("Step").clicked() {
-                pathfinder.step(&storage, tile_states.reborrow(), tile_parents.reborrow());
+                pathfinder.step(
+                    &floor_storages(&tilemaps),
+                    tile_states.reborrow(),
+                    tile_parents.reborrow(),
+                );
+                recorder.capture(
+                    &pathfinder,
+                    tile_states.as_readonly(),
+                    tile_terrains.as_readonly(),
+                    &storage,
+                );
             };
 
             ui.checkbox(&mut options.auto_enabled, "Auto");
+            if ui
+                .checkbox(&mut pathfinder.bypass_change_detection().live, "Live")
+                .changed()
+            {
+                pathfinder.set_changed();
+            }
 
             ui.add(Slider::new(&mut options.auto_speed, 0..=MAX_AUTO_SPEED).text("Speed"));
         });
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut inspector.enabled, "Inspector");
+            ui.checkbox(&mut overlay.parent_arrows, "Parent Arrows");
+            ui.checkbox(&mut frontier.enabled, "Frontier");
+        });
+        ui.horizontal(|ui| {
+            ui.label("Color By");
+            for mode in ColorMode::ALL {
+                ui.radio_value(&mut overlay.color_mode, mode, mode.name());
+            }
+            ui.checkbox(&mut overlay.labels, "Labels");
+        });
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut walker.enabled, "Walker");
+            ui.add(Slider::new(&mut walker.speed, 1.0..=20.0).text("Walk Speed"));
+            if ui.button("Walk Again").clicked() {
+                walker.restart();
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut smoothed.enabled, "Smooth Path");
+            if !smoothed.runs.is_empty() {
+                ui.label(format!(
+                    "{:.2} tiles found, {:.2} smoothed",
+                    smoothed.path_length, smoothed.smoothed_length
+                ));
+            }
+        });
+        ui.horizontal(|ui| {
+            if ui.button("HPA*").clicked() {
+                flush_path(tile_states.reborrow(), tile_parents.reborrow());
+                pathfinder.stop(options.algorithm);
+
+                if hpa.plan(tile_states.as_readonly(), &storage) {
+                    hpa.mark_path(tile_states.reborrow(), &storage);
+                } else {
+                    error!("hpa found no path");
+                }
+            }
+
+            ui.add(Slider::new(&mut hpa.cluster_size, 2..=16).text("Cluster Size"));
+            ui.checkbox(&mut hpa.overlay, "Overlay");
+        });
+        if hpa.entrance_count() > 0 {
+            ui.label(format!(
+                "{} entrances, {} nodes, {} abstract expansions, {} tiles",
+                hpa.entrance_count(),
+                hpa.node_count(),
+                hpa.expansions,
+                hpa.path.len()
+            ));
+        }
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut flow_field.enabled, "Flow Field");
+            ui.add_enabled_ui(flow_field.enabled, |ui| {
+                ui.checkbox(&mut flow_field.heatmap, "Heatmap");
+                ui.checkbox(&mut flow_field.arrows, "Arrows");
+                ui.label(format!("farthest cost {}", flow_field.max_cost));
+            });
+        });
+
+        ui.add_space(spacing);
+        ui.heading("Multi-Agent");
+        ui.separator();
+        ui.horizontal(|ui| {
+            ui.label("Solver");
+            ui.radio_value(&mut mapf.solver, MapfSolver::Cooperative, "Cooperative A*");
+            ui.radio_value(&mut mapf.solver, MapfSolver::Windowed, "WHCA*");
+            ui.radio_value(&mut mapf.solver, MapfSolver::ConflictBased, "CBS");
+        });
+        ui.add_enabled_ui(mapf.solver == MapfSolver::Windowed, |ui| {
+            ui.add(Slider::new(&mut mapf.window, 2..=32).text("Window"));
+        });
+        ui.horizontal(|ui| {
+            if ui.button("Solve").clicked()
+                && !mapf.solve(tile_states.as_readonly(), &storage, current_floor.0)
+            {
+                error!("mapf found no solution");
+            }
+
+            if ui.button("Clear").clicked() {
+                mapf.clear();
+            }
+
+            ui.checkbox(&mut mapf.playing, "Play");
+            ui.add(Slider::new(&mut mapf.speed, 0.5..=16.0).text("Speed"));
+        });
+        if let Some(solution) = mapf.solution() {
+            ui.label(format!(
+                "{} agents, sum of costs {}, makespan {}, {} conflicts resolved",
+                solution.agent_count(),
+                solution.sum_of_costs(),
+                solution.makespan(),
+                solution.resolved.len()
+            ));
+            if solution.unpaired > 0 {
+                ui.colored_label(
+                    Color32::YELLOW,
+                    format!(
+                        "{} starts or goals had no partner and were left out",
+                        solution.unpaired
+                    ),
+                );
+            }
+        }
+
+        ui.add_space(spacing);
+        ui.heading("Pursuit");
+        ui.separator();
+        ui.horizontal(|ui| {
+            ui.label("Goals");
+            ui.radio_value(
+                &mut pursuit.movement,
+                GoalMovement::RandomWalk,
+                "Random Walk",
+            );
+            ui.radio_value(&mut pursuit.movement, GoalMovement::Patrol, "Patrol");
+            ui.radio_value(&mut pursuit.movement, GoalMovement::Keyboard, "Arrow Keys");
+        });
+        ui.horizontal(|ui| {
+            ui.label("Replanning");
+            ui.radio_value(&mut pursuit.replanning, Replanning::Restart, "Restart");
+            ui.radio_value(
+                &mut pursuit.replanning,
+                Replanning::Incremental,
+                "Incremental",
+            );
+        });
+        ui.add(Slider::new(&mut pursuit.chaser_every, 1..=32).text("Chaser Ticks"));
+        ui.add(Slider::new(&mut pursuit.goal_every, 1..=32).text("Goal Ticks"));
+        ui.horizontal(|ui| {
+            ui.label("Obstacles");
+            ui.radio_value(&mut obstacles.mode, ObstacleMode::Off, "Off");
+            ui.radio_value(&mut obstacles.mode, ObstacleMode::Random, "Random");
+            ui.radio_value(&mut obstacles.mode, ObstacleMode::Ambush, "Ambush");
+        });
+        ui.add_enabled_ui(obstacles.mode != ObstacleMode::Off, |ui| {
+            ui.add(Slider::new(&mut obstacles.every, 1..=128).text("Obstacle Ticks"));
+            ui.add(Slider::new(&mut obstacles.lifetime, 1..=512).text("Obstacle Lifetime"));
+            ui.add_enabled_ui(obstacles.mode == ObstacleMode::Ambush, |ui| {
+                ui.add(Slider::new(&mut obstacles.lead, 1..=16).text("Ambush Lead"));
+            });
+        });
+        ui.horizontal(|ui| {
+            if pursuit.running() {
+                if ui.button("Stop").clicked() {
+                    pursuit.stop();
+                }
+                ui.label(format!(
+                    "{} expansions so far, {} obstacles up",
+                    pursuit.expansions(),
+                    obstacles.active()
+                ));
+            } else if ui.button("Start").clicked() {
+                flush_path(tile_states.reborrow(), tile_parents.reborrow());
+                pathfinder.stop(options.algorithm);
+
+                if !pursuit.begin(tile_states.reborrow(), &storage, current_floor.0) {
+                    error!("pursuit needs a start and a goal on this floor");
+                }
+            }
+        });
+        for result in &pursuit.results {
+            ui.label(format!(
+                "{}: caught in {} steps, {} replans, {} detours, {} expansions",
+                result.replanning.name(),
+                result.steps,
+                result.replans,
+                result.detours,
+                result.expansions
+            ));
+        }
+
+        ui.add_space(spacing);
+        ui.heading("Brush");
+        ui.separator();
+        ui.horizontal_wrapped(|ui| {
+            ui.radio_value(&mut brush.tool, BrushTool::Freehand, "Freehand");
+            ui.radio_value(&mut brush.tool, BrushTool::Line, "Line");
+            ui.radio_value(&mut brush.tool, BrushTool::Rectangle, "Rectangle");
+            ui.radio_value(
+                &mut brush.tool,
+                BrushTool::FilledRectangle,
+                "Filled Rectangle",
+            );
+            ui.radio_value(&mut brush.tool, BrushTool::Circle, "Circle");
+            ui.radio_value(&mut brush.tool, BrushTool::FilledCircle, "Filled Circle");
+            ui.radio_value(&mut brush.tool, BrushTool::Fill, "Flood Fill");
+        });
+        Grid::new("paints").show(ui, |ui| {
+            for (i, (state, label)) in PAINTS.into_iter().enumerate() {
+                ui.selectable_value(&mut brush.paint, state, label);
+                if i % 5 == 4 {
+                    ui.end_row();
+                }
+            }
+        });
+        ui.add_enabled_ui(brush.tool != BrushTool::Fill, |ui| {
+            ui.add(Slider::new(&mut brush.size, 1..=8).text("Size"));
+        });
 
         ui.add_space(spacing);
         ui.heading("Map Generation");
         ui.separator();
+        ui.horizontal(|ui| {
+            ui.label("Floor");
+            for floor in 0..FLOOR_COUNT {
+                if ui
+                    .selectable_label(current_floor.0 == floor, floor.to_string())
+                    .clicked()
+                {
+                    current_floor.0 = floor;
+                }
+            }
+        });
+        ui.horizontal(|ui| {
+            if ui
+                .add_enabled(history.undo_len() > 0, Button::new("Undo"))
+                .clicked()
+                && history.undo(tile_states.reborrow(), tile_terrains.reborrow())
+            {
+                stop_search(
+                    &mut pathfinder,
+                    tile_states.reborrow(),
+                    tile_parents.reborrow(),
+                );
+            }
+
+            if ui
+                .add_enabled(history.redo_len() > 0, Button::new("Redo"))
+                .clicked()
+                && history.redo(tile_states.reborrow(), tile_terrains.reborrow())
+            {
+                stop_search(
+                    &mut pathfinder,
+                    tile_states.reborrow(),
+                    tile_parents.reborrow(),
+                );
+            }
+
+            ui.label(format!(
+                "{} to undo, {} to redo",
+                history.undo_len(),
+                history.redo_len()
+            ));
+        });
         ui.horizontal(|ui| {
             if ui.button("Flush").clicked() {
                 flush_path(tile_states.reborrow(), tile_parents.reborrow());
+                hpa.clear();
             }
 
             if ui.button("Empty").clicked() {
-                generate_flat(tile_states.reborrow(), TileState::Empty);
+                let snapshot = History::snapshot(
+                    tile_states.as_readonly(),
+                    tile_terrains.as_readonly(),
+                    &storage,
+                );
+                generate_flat(
+                    tile_states.reborrow(),
+                    tile_terrains.reborrow(),
+                    &storage,
+                    TileState::Empty,
+                );
                 flush_path(tile_states.reborrow(), tile_parents.reborrow());
                 pathfinder.stop(options.algorithm);
+                history.commit_since(
+                    snapshot,
+                    tile_states.as_readonly(),
+                    tile_terrains.as_readonly(),
+                );
             }
 
             if ui.button("Wall").clicked() {
-                generate_flat(tile_states.reborrow(), TileState::Wall);
+                let snapshot = History::snapshot(
+                    tile_states.as_readonly(),
+                    tile_terrains.as_readonly(),
+                    &storage,
+                );
+                generate_flat(
+                    tile_states.reborrow(),
+                    tile_terrains.reborrow(),
+                    &storage,
+                    TileState::Wall,
+                );
                 flush_path(tile_states.reborrow(), tile_parents.reborrow());
                 pathfinder.stop(options.algorithm);
+                history.commit_since(
+                    snapshot,
+                    tile_states.as_readonly(),
+                    tile_terrains.as_readonly(),
+                );
             }
 
             if ui.button("Noise").clicked() {
+                let snapshot = History::snapshot(
+                    tile_states.as_readonly(),
+                    tile_terrains.as_readonly(),
+                    &storage,
+                );
                 generate_noise(
                     tile_states.reborrow(),
-                    tiles_positions.reborrow(),
-                    options.noise_scale,
-                    options.noise_threshold,
+                    tile_terrains.reborrow(),
+                    &storage,
+                    &options.noise,
                 );
                 flush_path(tile_states.reborrow(), tile_parents.reborrow());
                 pathfinder.stop(options.algorithm);
+                history.commit_since(
+                    snapshot,
+                    tile_states.as_readonly(),
+                    tile_terrains.as_readonly(),
+                );
             }
 
             if ui.button("Maze").clicked() {
-                generate_maze(tile_states.reborrow(), tiles_positions.reborrow(), &storage);
+                let snapshot = History::snapshot(
+                    tile_states.as_readonly(),
+                    tile_terrains.as_readonly(),
+                    &storage,
+                );
+                generate_maze(tile_states.reborrow(), tile_terrains.reborrow(), &storage);
                 flush_path(tile_states.reborrow(), tile_parents.reborrow());
                 pathfinder.stop(options.algorithm);
+                history.commit_since(
+                    snapshot,
+                    tile_states.as_readonly(),
+                    tile_terrains.as_readonly(),
+                );
             }
         });
-        ui.add(Slider::new(&mut options.noise_scale, 1.0..=10.0).text("Noise Scale"));
-        ui.add(Slider::new(&mut options.noise_threshold, -1.0..=1.0).text("Noise Threshold"));
+        ui.horizontal(|ui| {
+            ui.label("Noise Fractal");
+            ui.radio_value(&mut options.noise.fractal, FractalOption::Single, "Single");
+            ui.radio_value(&mut options.noise.fractal, FractalOption::Fbm, "fBm");
+            ui.radio_value(&mut options.noise.fractal, FractalOption::Ridged, "Ridged");
+        });
+        ui.add(Slider::new(&mut options.noise.scale, 1.0..=10.0).text("Noise Scale"));
+        ui.add_enabled_ui(options.noise.fractal != FractalOption::Single, |ui| {
+            ui.add(Slider::new(&mut options.noise.octaves, 1..=8).text("Octaves"));
+            ui.add(Slider::new(&mut options.noise.lacunarity, 1.0..=4.0).text("Lacunarity"));
+            ui.add(Slider::new(&mut options.noise.persistence, 0.0..=1.0).text("Persistence"));
+        });
+        ui.add(
+            Slider::new(&mut options.noise.forest_threshold, -1.0..=1.0).text("Forest Threshold"),
+        );
+        ui.add(
+            Slider::new(&mut options.noise.mountain_threshold, -1.0..=1.0)
+                .text("Mountain Threshold"),
+        );
+        ui.add(Slider::new(&mut options.noise.wall_threshold, -1.0..=1.0).text("Wall Threshold"));
+
+        ui.horizontal(|ui| {
+            if ui.button("Learn").clicked() {
+                options.wfc_sample = WfcSample::capture(
+                    tile_states.reborrow(),
+                    tile_terrains.reborrow(),
+                    &storage,
+                    options.wfc_sample_size,
+                );
+            }
+
+            if ui.button("Load").clicked() {
+                match WfcSample::load(&options.wfc_sample_path) {
+                    Ok(sample) => options.wfc_sample = sample,
+                    Err(error) => error!("failed to load wfc sample: {}", error),
+                }
+            }
+
+            ui.text_edit_singleline(&mut options.wfc_sample_path);
+
+            if ui
+                .add_enabled(!options.wfc_sample.is_empty(), Button::new("WFC"))
+                .clicked()
+            {
+                let snapshot = History::snapshot(
+                    tile_states.as_readonly(),
+                    tile_terrains.as_readonly(),
+                    &storage,
+                );
+                if generate_wfc(
+                    tile_states.reborrow(),
+                    tile_terrains.reborrow(),
+                    &storage,
+                    &options.wfc_sample,
+                ) {
+                    flush_path(tile_states.reborrow(), tile_parents.reborrow());
+                    pathfinder.stop(options.algorithm);
+                    history.commit_since(
+                        snapshot,
+                        tile_states.as_readonly(),
+                        tile_terrains.as_readonly(),
+                    );
+                } else {
+                    error!("wfc gave up after too many contradictions");
+                }
+            }
+        });
+        ui.add(Slider::new(&mut options.wfc_sample_size, 2..=16).text("WFC Sample Size"));
+
+        ui.add_space(spacing);
+        ui.heading("Files");
+        ui.separator();
+        ui.horizontal(|ui| {
+            if ui.button("Export PNG").clicked()
+                && let Err(error) = export_png(
+                    tile_states.as_readonly(),
+                    tile_terrains.as_readonly(),
+                    &storage,
+                    &options.image_path,
+                    recorder.pixels_per_tile,
+                    options.image_overlays,
+                )
+            {
+                error!("failed to export png: {}", error);
+            }
+
+            if ui.button("Import PNG").clicked() {
+                let snapshot = History::snapshot(
+                    tile_states.as_readonly(),
+                    tile_terrains.as_readonly(),
+                    &storage,
+                );
+                match import_png(
+                    tile_states.reborrow(),
+                    tile_terrains.reborrow(),
+                    &storage,
+                    &options.image_path,
+                ) {
+                    Ok(()) => {
+                        flush_path(tile_states.reborrow(), tile_parents.reborrow());
+                        pathfinder.stop(options.algorithm);
+                        history.commit_since(
+                            snapshot,
+                            tile_states.as_readonly(),
+                            tile_terrains.as_readonly(),
+                        );
+                    }
+                    Err(error) => error!("failed to import png: {}", error),
+                }
+            }
+
+            ui.text_edit_singleline(&mut options.image_path);
+        });
+        ui.horizontal(|ui| {
+            // Shared by PNG export and recorded GIF frames
+            ui.add(Slider::new(&mut recorder.pixels_per_tile, 1..=32).text("Pixels Per Tile"));
+            ui.checkbox(&mut options.image_overlays.visited, "Visited");
+            ui.checkbox(&mut options.image_overlays.queued, "Queued");
+            ui.checkbox(&mut options.image_overlays.final_path, "Final Path");
+        });
+
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut recorder.enabled, "Record");
+
+            if ui.button("Save GIF").clicked()
+                && let Err(error) = recorder.save_gif(&options.gif_path)
+            {
+                error!("failed to save gif: {}", error);
+            }
+
+            if ui.button("Clear").clicked() {
+                recorder.clear();
+            }
+
+            ui.text_edit_singleline(&mut options.gif_path);
+        });
+        ui.horizontal(|ui| {
+            ui.label(format!("{} frames", recorder.frame_count()));
+            ui.add(Slider::new(&mut recorder.every, 1..=20).text("Every N Steps"));
+            ui.add(Slider::new(&mut recorder.frame_delay_ms, 10..=500).text("Delay (ms)"));
+        });
+
+        ui.horizontal(|ui| {
+            if ui.button("Export Trace").clicked()
+                && let Err(error) = pathfinder.trace.save(&options.trace_path)
+            {
+                error!("failed to export trace: {}", error);
+            }
+
+            ui.text_edit_singleline(&mut options.trace_path);
+        });
     });
 
     Window::new("Information").show(contexts.ctx_mut().unwrap(), |ui| {
         let controls = [
             ("S", "Place Start"),
             ("E", "Place Goal"),
-            ("Left", "Place Wall"),
-            ("Right", "Place Empty"),
+            ("P", "Place Portal Pair"),
+            ("IJKL", "Place One Way"),
+            ("1-3", "Place Key"),
+            ("Shift 1-3", "Place Door"),
+            ("H", "Place Stairs"),
+            ("PgUp PgDn", "Change Floor"),
+            ("Arrows", "Steer Goals"),
+            ("Ctrl Z", "Undo"),
+            ("Ctrl Shift Z", "Redo"),
+            ("Left", "Paint with Brush"),
+            ("Right", "Erase with Brush"),
+            ("Drag S/E", "Move Start/Goal"),
             ("Middle", "Move"),
             ("Scroll", "Zoom"),
         ];
@@ -242,9 +837,12 @@ fn options_menu(
 fn auto_step(
     mut pathfinder: ResMut<Pathfinder>,
     mut options: ResMut<Options>,
-    tile_states: Query<&mut TileState>,
+    mut recorder: ResMut<Recorder>,
+    mut tile_states: Query<&mut TileState>,
+    tile_terrains: Query<&TileTerrain>,
     tile_parents: Query<&mut TileParent>,
-    tile_storage: Single<&TileStorage>,
+    tile_storage: Single<&TileStorage, With<ActiveFloor>>,
+    tilemaps: Query<(&TileStorage, &Floor)>,
 ) {
     if !options.auto_enabled {
         options.current_tick = 0;
@@ -254,6 +852,16 @@ fn auto_step(
     options.current_tick += 1;
     if options.current_tick >= (MAX_AUTO_SPEED - options.auto_speed) {
         options.current_tick = 0;
-        pathfinder.step(&tile_storage, tile_states, tile_parents);
+        pathfinder.step(
+            &floor_storages(&tilemaps),
+            tile_states.reborrow(),
+            tile_parents,
+        );
+        recorder.capture(
+            &pathfinder,
+            tile_states.as_readonly(),
+            tile_terrains,
+            &tile_storage,
+        );
     }
 }
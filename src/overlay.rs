@@ -0,0 +1,234 @@
+use bevy::{color::palettes::basic, prelude::*};
+use bevy_ecs_tilemap::prelude::*;
+use bevy_egui::{
+    EguiContexts, EguiPrimaryContextPass,
+    egui::{Align2, Color32, FontId, LayerId, Pos2},
+};
+
+use crate::{
+    ActiveFloor, CurrentFloor, TileParent, TileState, is_search_state,
+    pathfinder::{Pathfinder, TileRecord},
+    tile_center,
+};
+
+pub fn overlay_plugin(app: &mut App) {
+    app.init_resource::<Overlay>()
+        .add_systems(Update, draw_parent_arrows)
+        .add_systems(EguiPrimaryContextPass, draw_labels);
+}
+
+/**
+ * What searched tiles are colored and labeled by
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorMode {
+    // Distance to the closest goal, the usual coloring
+    #[default]
+    Heuristic,
+    // Step the tile was first stepped on
+    ExpansionOrder,
+    CostSoFar,
+    FScore,
+    // Times the tile was stepped on, which only goes past one with keys or any angle searches
+    Expansions,
+}
+
+impl ColorMode {
+    pub const ALL: [ColorMode; 5] = [
+        ColorMode::Heuristic,
+        ColorMode::ExpansionOrder,
+        ColorMode::CostSoFar,
+        ColorMode::FScore,
+        ColorMode::Expansions,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ColorMode::Heuristic => "Heuristic",
+            ColorMode::ExpansionOrder => "Expansion Order",
+            ColorMode::CostSoFar => "Cost So Far",
+            ColorMode::FScore => "F Score",
+            ColorMode::Expansions => "Expansions",
+        }
+    }
+
+    /**
+     * The number a tile is colored and labeled by, None if the search hasn't gotten that far with it
+     */
+    pub fn value(self, record: &TileRecord) -> Option<f32> {
+        match self {
+            ColorMode::Heuristic => Some(record.distance as f32),
+            ColorMode::ExpansionOrder => record.visited.map(|step| step as f32),
+            ColorMode::CostSoFar => record.cost,
+            ColorMode::FScore => record.score(),
+            ColorMode::Expansions => Some(record.expansions as f32),
+        }
+    }
+
+    fn label(self, value: f32) -> String {
+        match self {
+            ColorMode::CostSoFar | ColorMode::FScore => format!("{:.1}", value),
+            _ => format!("{}", value as u32),
+        }
+    }
+}
+
+/**
+ * Extra views of the search drawn on top of the tiles
+ */
+#[derive(Debug, Default, Resource)]
+pub struct Overlay {
+    // An arrow from every reached tile back to its parent, showing the whole search tree
+    pub parent_arrows: bool,
+    pub color_mode: ColorMode,
+    // Writes the color mode's number on every searched tile
+    pub labels: bool,
+}
+
+/**
+ * System that draws the search tree on the current floor as arrows pointing from each tile to its parent
+ */
+fn draw_parent_arrows(
+    mut gizmos: Gizmos,
+    overlay: Res<Overlay>,
+    pathfinder: Res<Pathfinder>,
+    current: Res<CurrentFloor>,
+    tiles: Query<(&TileState, &TileParent)>,
+    tilemap: Single<
+        (
+            (
+                &TilemapSize,
+                &TilemapGridSize,
+                &TilemapTileSize,
+                &TilemapType,
+                &TilemapAnchor,
+                &Transform,
+            ),
+            &TileStorage,
+        ),
+        With<ActiveFloor>,
+    >,
+) {
+    if !overlay.parent_arrows {
+        return;
+    }
+
+    let (layout, storage) = *tilemap;
+    let grid_size = layout.1;
+    let color = basic::BLACK.with_alpha(0.6);
+
+    for y in 0..storage.size.y {
+        for x in 0..storage.size.x {
+            let pos = TilePos::new(x, y);
+            let (state, parent) = tiles.get(storage.checked_get(&pos).unwrap()).unwrap();
+            let TileParent(Some(parent)) = *parent else {
+                continue;
+            };
+            if !matches!(
+                state,
+                TileState::Queued(_) | TileState::Visited(_) | TileState::Final(_)
+            ) {
+                continue;
+            }
+
+            // An arrow to a portal partner or across a wrapping edge would cut across the whole map
+            if pathfinder.jumped(pos, current.0) {
+                continue;
+            }
+
+            // Stop short of the parent's center so arrows into the same tile don't pile up on one point
+            let from = tile_center(layout, pos);
+            let to = from.lerp(tile_center(layout, parent), 0.75);
+
+            gizmos
+                .arrow_2d(from, to, color)
+                .with_tip_length(grid_size.x * 0.25);
+        }
+    }
+}
+
+/**
+ * System that writes the color mode's number over every searched tile on the current floor, once zoomed in enough to
+ * read them
+ */
+fn draw_labels(
+    mut contexts: EguiContexts,
+    overlay: Res<Overlay>,
+    pathfinder: Res<Pathfinder>,
+    current: Res<CurrentFloor>,
+    tile_states: Query<&TileState>,
+    camera: Single<(&Camera, &GlobalTransform, &Projection)>,
+    tilemap: Single<
+        (
+            (
+                &TilemapSize,
+                &TilemapGridSize,
+                &TilemapTileSize,
+                &TilemapType,
+                &TilemapAnchor,
+                &Transform,
+            ),
+            &TileStorage,
+        ),
+        With<ActiveFloor>,
+    >,
+) {
+    if !overlay.labels {
+        return;
+    }
+
+    let (camera, camera_transform, projection) = *camera;
+    let Projection::Orthographic(ortho) = projection else {
+        return;
+    };
+    let (layout, storage) = *tilemap;
+
+    let tile_size = layout.1.x / ortho.scale;
+    if tile_size < 16.0 {
+        return;
+    }
+
+    let painter = contexts
+        .ctx_mut()
+        .unwrap()
+        .layer_painter(LayerId::background());
+    let font = FontId::proportional(tile_size * 0.35);
+    let mode = overlay.color_mode;
+
+    for y in 0..storage.size.y {
+        for x in 0..storage.size.x {
+            let pos = TilePos::new(x, y);
+            let state = *tile_states.get(storage.checked_get(&pos).unwrap()).unwrap();
+
+            let value = match state {
+                TileState::Queued(distance)
+                | TileState::Visited(distance)
+                | TileState::Final(distance)
+                    if mode == ColorMode::Heuristic =>
+                {
+                    Some(distance as f32)
+                }
+                _ if is_search_state(state) => pathfinder
+                    .record(pos, current.0)
+                    .and_then(|record| mode.value(record)),
+                _ => None,
+            };
+            let Some(value) = value else {
+                continue;
+            };
+
+            let center = tile_center(layout, pos).extend(0.0);
+            let Ok(screen) = camera.world_to_viewport(camera_transform, center) else {
+                continue;
+            };
+
+            painter.text(
+                Pos2::new(screen.x, screen.y),
+                Align2::CENTER_CENTER,
+                mode.label(value),
+                font.clone(),
+                Color32::BLACK,
+            );
+        }
+    }
+}
@@ -1,44 +1,71 @@
 use bevy::prelude::*;
-use bevy_ecs_tilemap::tiles::{TilePos, TileStorage};
+use bevy_ecs_tilemap::{
+    map::TilemapSize,
+    tiles::{TilePos, TileStorage},
+};
 use rand::seq::IteratorRandom;
-use std::{collections::HashSet, ops::ControlFlow};
+use std::{
+    collections::{HashMap, HashSet},
+    ops::ControlFlow,
+};
 
 use crate::{
-    TileParent, TileState,
+    Floor, TileParent, TileState, TileTerrain,
     algorithm::{Algorithm, AlgorithmOption},
-    pathfinder_tile::PathfinderTile,
+    floor_storages,
+    generate::flush_path,
+    pathfinder_tile::{Cardinal, KeyColor, PathfinderTile, Topology, offset},
+    smoothing::{blocks_sight, line, line_of_sight, tile_distance},
+    trace::{NeighborOutcome, Trace, pos},
 };
 
 pub fn pathfinder_plugin(app: &mut App) {
-    app.init_resource::<Pathfinder>()
-        .add_systems(Update, update_endpoints);
+    app.init_resource::<Pathfinder>().add_systems(
+        Update,
+        (update_endpoints, update_terrain, live_search).chain(),
+    );
 }
 
 /**
  * This system watches all TileStates, and update's the pathfinder's internal endpoints list with any added/removed endpoint tiles
  */
 fn update_endpoints(
-    mut tiles_query: Query<(&TileState, &TilePos), Changed<TileState>>,
+    mut tiles_query: Query<(&TileState, &TilePos, &Floor), Changed<TileState>>,
     mut pathfinder: ResMut<Pathfinder>,
 ) {
-    for (state, &pos) in tiles_query.iter_mut() {
-        if pathfinder.start_tiles.remove(&PathfinderTile::zero(pos)) {
-            debug!("removed start tile {}", PathfinderTile::zero(pos));
+    for (state, &pos, &Floor(floor)) in tiles_query.iter_mut() {
+        let tile = PathfinderTile::zero(pos, floor);
+
+        if pathfinder.start_tiles.remove(&tile) {
+            debug!("removed start tile {}", tile);
+            pathfinder.endpoints_moved = true;
         }
 
-        if pathfinder.goal_tiles.remove(&PathfinderTile::zero(pos)) {
-            debug!("removed goal tile {}", PathfinderTile::zero(pos));
+        if pathfinder.goal_tiles.remove(&tile) {
+            debug!("removed goal tile {}", tile);
+            pathfinder.endpoints_moved = true;
+        }
+
+        if let Some(pair) = pathfinder.portals.remove(&tile) {
+            debug!("removed portal {} tile {}", pair, tile);
         }
 
         match state {
             TileState::Start => {
-                debug!("added start tile {}", PathfinderTile::zero(pos));
-                pathfinder.start_tiles.insert(PathfinderTile::zero(pos));
+                debug!("added start tile {}", tile);
+                pathfinder.start_tiles.insert(tile);
+                pathfinder.endpoints_moved = true;
             }
 
             TileState::Goal => {
-                debug!("added goal tile {}", PathfinderTile::zero(pos));
-                pathfinder.goal_tiles.insert(PathfinderTile::zero(pos));
+                debug!("added goal tile {}", tile);
+                pathfinder.goal_tiles.insert(tile);
+                pathfinder.endpoints_moved = true;
+            }
+
+            TileState::Portal(pair) => {
+                debug!("added portal {} tile {}", pair, tile);
+                pathfinder.portals.insert(tile, *pair);
             }
 
             _ => {}
@@ -46,19 +73,115 @@ fn update_endpoints(
     }
 }
 
+/**
+ * System that keeps the pathfinder's copy of the terrain up to date, only tiles rougher than plains are kept
+ */
+fn update_terrain(
+    tiles_query: Query<(&TileTerrain, &TilePos, &Floor), Changed<TileTerrain>>,
+    mut pathfinder: ResMut<Pathfinder>,
+) {
+    for (&terrain, &pos, &Floor(floor)) in tiles_query.iter() {
+        let tile = PathfinderTile::zero(pos, floor);
+        if terrain == TileTerrain::Plains {
+            pathfinder.terrain.remove(&tile);
+        } else {
+            pathfinder.terrain.insert(tile, terrain);
+        }
+    }
+}
+
+/**
+ * System that reruns the whole search whenever a start or goal tile moves, so in live mode the path follows along
+ */
+fn live_search(
+    mut pathfinder: ResMut<Pathfinder>,
+    mut tile_states: Query<&mut TileState>,
+    mut tile_parents: Query<&mut TileParent>,
+    tilemaps: Query<(&TileStorage, &Floor)>,
+) {
+    // Only touch the pathfinder mutably when there's something to do, so it isn't marked changed every frame
+    if !pathfinder.endpoints_moved {
+        return;
+    }
+    pathfinder.endpoints_moved = false;
+    if !pathfinder.live {
+        return;
+    }
+
+    let option = pathfinder.option;
+    pathfinder.restart(option);
+    flush_path(tile_states.reborrow(), tile_parents.reborrow());
+
+    let storages = floor_storages(&tilemaps);
+    while !pathfinder.complete {
+        pathfinder.step(&storages, tile_states.reborrow(), tile_parents.reborrow());
+    }
+}
+
+/**
+ * What the search did with a tile across all its layers, kept for inspecting it after the fact
+ */
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TileRecord {
+    // Step the tile was last queued on, and the one it was first stepped on
+    pub queued: Option<usize>,
+    pub visited: Option<usize>,
+    // Cheapest cost found to reach it and its heuristic distance, in whichever layer that was
+    pub cost: Option<f32>,
+    pub distance: u32,
+    // Times it was stepped on, more than once when keys or any angle searches bring it back
+    pub expansions: u32,
+}
+
+impl TileRecord {
+    pub fn score(&self) -> Option<f32> {
+        self.cost.map(|cost| cost + self.distance as f32)
+    }
+}
+
 #[derive(Resource)]
 pub struct Pathfinder {
     // Used to do the actual path finding
+    option: AlgorithmOption,
     algorithm: Box<dyn Algorithm + Sync + Send>,
     visited: HashSet<PathfinderTile>,
+    // Tiles that have been stepped on, any angle searches can queue a tile twice so this skips the leftover
+    closed: HashSet<PathfinderTile>,
+    // Parents per search state, since with keys a tile can be reached once per inventory
+    parents: HashMap<PathfinderTile, PathfinderTile>,
+    // Cheapest known cost to reach each search state
+    costs: HashMap<PathfinderTile, f32>,
+    // Tiles reached through a portal, stairs or a wrapping edge rather than a straight line from their parent
+    jumps: HashSet<PathfinderTile>,
+    // Lazy Theta* parents that haven't been checked for line of sight yet, and the neighbor to fall back on
+    fallbacks: HashMap<PathfinderTile, PathfinderTile>,
+    // Keyed by tiles with no keys, so every layer of a tile shares one record
+    records: HashMap<PathfinderTile, TileRecord>,
+    // Tiles with no keys whose records changed this search, in order and with repeats, so views can redraw just those
+    touched: Vec<PathfinderTile>,
+    // Goes up whenever the records start over
+    searches: usize,
+    pub topology: Topology,
 
     // Updated by update_endpoints system
     start_tiles: HashSet<PathfinderTile>,
     goal_tiles: HashSet<PathfinderTile>,
+    portals: HashMap<PathfinderTile, u32>,
+    // Updated by update_terrain system, keyed by tiles with no keys
+    terrain: HashMap<PathfinderTile, TileTerrain>,
+    endpoints_moved: bool,
+
+    // Reruns to completion whenever the endpoints move
+    pub live: bool,
 
     // Bookkeeping for UI
     pub step: usize,
     pub complete: bool,
+    pub trace: Trace,
+    // Keys held by the last tile stepped on, which layer of the state space is being explored
+    pub layer: u8,
+    // Filled in from start to goal once a path is found
+    pub path: Vec<PathfinderTile>,
 }
 
 impl Pathfinder {
@@ -66,11 +189,23 @@ impl Pathfinder {
      *  Reset pathfinder with automatically starting
      */
     pub fn restart(&mut self, algorithm: AlgorithmOption) {
+        self.option = algorithm;
         self.algorithm = algorithm.into();
         self.visited.clear();
+        self.closed.clear();
+        self.parents.clear();
+        self.costs.clear();
+        self.jumps.clear();
+        self.fallbacks.clear();
+        self.records.clear();
+        self.touched.clear();
+        self.searches += 1;
 
         self.step = 0;
         self.complete = false;
+        self.layer = 0;
+        self.path.clear();
+        self.trace = Trace::new(algorithm, self.topology);
     }
 
     /**
@@ -81,12 +216,104 @@ impl Pathfinder {
         self.complete = true;
     }
 
+    /**
+     * Every other portal with this pair number, leaving out the tile itself
+     */
+    fn portal_partners(
+        &self,
+        tile: PathfinderTile,
+        pair: u32,
+    ) -> impl Iterator<Item = PathfinderTile> + '_ {
+        self.portals
+            .iter()
+            .filter(move |&(&other, &other_pair)| other_pair == pair && other != tile)
+            .map(|(&other, _)| other)
+    }
+
+    /**
+     * Each linked pair of portals once, along with their pair number
+     */
+    pub fn portal_links(&self) -> impl Iterator<Item = (u32, PathfinderTile, PathfinderTile)> + '_ {
+        self.portals.iter().flat_map(move |(&tile, &pair)| {
+            self.portal_partners(tile, pair)
+                // Only report each link from one side
+                .filter(move |other| {
+                    (other.floor, other.pos.x, other.pos.y) > (tile.floor, tile.pos.x, tile.pos.y)
+                })
+                .map(move |other| (pair, tile, other))
+        })
+    }
+
+    /**
+     * Whether a tile on the found path was reached by teleporting rather than walking from the one before it
+     */
+    pub fn is_jump(&self, tile: PathfinderTile) -> bool {
+        self.jumps.contains(&tile)
+    }
+
+    /**
+     * What the current search has done with a tile, None if it was never queued
+     */
+    pub fn record(&self, pos: TilePos, floor: u32) -> Option<&TileRecord> {
+        self.records.get(&PathfinderTile::zero(pos, floor))
+    }
+
+    pub fn option(&self) -> AlgorithmOption {
+        self.option
+    }
+
+    /**
+     * Pending tiles in the order they'd be stepped on, leaving out ones already stepped on that will just be skipped
+     */
+    pub fn frontier(&self) -> Vec<(PathfinderTile, Option<f32>)> {
+        self.algorithm
+            .frontier()
+            .into_iter()
+            .filter(|(tile, _)| !self.closed.contains(tile))
+            .collect()
+    }
+
+    pub fn records(&self) -> impl Iterator<Item = &TileRecord> {
+        self.records.values()
+    }
+
+    /**
+     * Whether a tile was reached through a portal or a wrapping edge in any layer, so its parent isn't next to it
+     */
+    pub fn jumped(&self, pos: TilePos, floor: u32) -> bool {
+        // Look up every possible set of held keys directly instead of scanning all the jumps
+        let all_keys = KeyColor::ALL
+            .iter()
+            .fold(0, |keys, color| keys | color.bit());
+        (0..=all_keys).any(|keys| {
+            self.jumps.contains(&PathfinderTile {
+                keys,
+                ..PathfinderTile::zero(pos, floor)
+            })
+        })
+    }
+
+    pub fn touched(&self) -> &[PathfinderTile] {
+        &self.touched
+    }
+
+    pub fn searches(&self) -> usize {
+        self.searches
+    }
+
+    /**
+     * Heuristic distance from a tile to the closest goal, the same one the search uses on a map of this size
+     */
+    pub fn heuristic(&self, pos: TilePos, size: TilemapSize) -> u32 {
+        PathfinderTile::new(pos, &self.goal_tiles, self.topology, size).distance
+    }
+
     /**
      * Perform a loop of the pathfinder
      */
     pub fn step(
         &mut self,
-        tile_storage: &TileStorage,
+        storages: &[&TileStorage],
         mut tile_states: Query<&mut TileState>,
         mut tile_parents: Query<&mut TileParent>,
     ) {
@@ -100,28 +327,41 @@ impl Pathfinder {
         // We're not complete and have an empty queue, meaning we haven't started yet
         // So pick a random starting tile and queue it
         if self.visited.is_empty() {
+            for storage in storages {
+                self.trace.push_floor((0..storage.size.y).map(|y| {
+                    (0..storage.size.x)
+                        .map(|x| {
+                            let entity = storage.checked_get(&TilePos::new(x, y)).unwrap();
+                            *tile_states.get(entity).unwrap()
+                        })
+                        .collect()
+                }));
+            }
+            self.trace.goals = self.goal_tiles.iter().copied().map(pos).collect();
+
             if let Some(&start_tile) = self.start_tiles.iter().choose(&mut rand::rng()) {
                 debug!("selected start tile {}", start_tile);
+                self.trace.start = Some(pos(start_tile));
                 self.algorithm.insert(start_tile);
                 self.visited.insert(start_tile);
+                self.costs.insert(start_tile, 0.0);
+                self.note_queued(start_tile);
             } else {
                 debug!("no start tiles to select from");
             }
         }
 
         // Keep stepping until we get told to stop
-        if let ControlFlow::Break(mut next_pos) = self.step_internal(
-            tile_storage,
-            tile_states.reborrow(),
-            tile_parents.reborrow(),
-        ) {
+        if let ControlFlow::Break(mut next_tile) =
+            self.step_internal(storages, tile_states.reborrow(), tile_parents.reborrow())
+        {
             // Don't step anymore after this
             self.complete = true;
 
-            // If we are given a goal position back, try to follow the parent chain and mark them, filling out the full found path
-            while let Some(current_pos) = next_pos {
-                let Some(entity) = tile_storage.checked_get(&current_pos) else {
-                    next_pos = None;
+            // If we are given a goal tile back, try to follow the parent chain and mark them, filling out the full found path
+            while let Some(current_tile) = next_tile {
+                let Some(entity) = tile_entity(storages, current_tile) else {
+                    next_tile = None;
                     continue;
                 };
 
@@ -131,9 +371,41 @@ impl Pathfinder {
                     *tile_state = TileState::Final(distance);
                 }
 
-                // Loop to next parent
-                next_pos = tile_parents.get_mut(entity).unwrap().0;
+                self.path.push(current_tile);
+
+                // Loop to next parent, from the parents map since TileParent only knows one layer
+                next_tile = self.parents.get(&current_tile).copied();
+
+                // Any angle parents can be far away, so fill in the straight line between them too
+                if let Some(parent) = next_tile
+                    && !self.jumps.contains(&current_tile)
+                {
+                    for between in line(parent.pos, current_tile.pos) {
+                        if between == current_tile.pos {
+                            continue;
+                        }
+
+                        let entity = storages[current_tile.floor as usize]
+                            .checked_get(&between)
+                            .unwrap();
+                        let mut tile_state = tile_states.get_mut(entity).unwrap();
+
+                        match *tile_state {
+                            TileState::Queued(distance) | TileState::Visited(distance) => {
+                                *tile_state = TileState::Final(distance);
+                            }
+                            TileState::Empty => {
+                                *tile_state = TileState::Final(current_tile.distance)
+                            }
+                            _ => {}
+                        }
+                    }
+                }
             }
+
+            // The parent chain runs backwards, but the path should read from start to goal
+            self.path.reverse();
+            self.trace.path = self.path.iter().copied().map(pos).collect();
         }
 
         debug!("----- pathfinder step done = {} -----", self.step);
@@ -146,60 +418,190 @@ impl Pathfinder {
      */
     fn step_internal(
         &mut self,
-        storage: &TileStorage,
+        storages: &[&TileStorage],
         mut tile_states: Query<&mut TileState>,
         mut tile_parents: Query<&mut TileParent>,
-    ) -> ControlFlow<Option<TilePos>> {
-        // Ran out of tiles in the queue, break without a found path
-        let Some(tile) = self.algorithm.next() else {
-            debug!("no more tiles in queue");
-            return ControlFlow::Break(None);
+    ) -> ControlFlow<Option<PathfinderTile>> {
+        let tile = loop {
+            // Ran out of tiles in the queue, break without a found path
+            let Some(tile) = self.algorithm.next() else {
+                debug!("no more tiles in queue");
+                self.trace.begin_step(self.step, None);
+                return ControlFlow::Break(None);
+            };
+
+            if self.closed.insert(tile) {
+                break tile;
+            }
+            debug!("skipping outdated {}", tile);
         };
+        let tile = self.check_lazy_parent(tile, storages, &tile_states, tile_parents.reborrow());
 
         debug!("stepping on tile {}", tile);
-
-        // Hit a goal tile, break with a found path
-        if self.goal_tiles.contains(&tile) {
+        self.trace.begin_step(self.step, Some(tile));
+        self.layer = tile.keys;
+
+        let key = PathfinderTile::zero(tile.pos, tile.floor);
+        let record = self.records.entry(key).or_default();
+        record.visited.get_or_insert(self.step);
+        record.expansions += 1;
+        self.touched.push(key);
+
+        // Hit a goal tile, break with a found path, goals don't care what keys are held
+        if self
+            .goal_tiles
+            .contains(&PathfinderTile::zero(tile.pos, tile.floor))
+        {
             debug!("reached goal {}", tile);
-            return ControlFlow::Break(Some(tile.pos));
+            self.trace.reached_goal();
+            return ControlFlow::Break(Some(tile));
         }
 
-        for neighbor in tile.neighbors(&self.goal_tiles) {
-            // Don't requeue tiles we've already visited
-            if self.visited.contains(&neighbor) {
-                debug!("neighbor skip {}", neighbor);
+        let current_entity = tile_entity(storages, tile).unwrap();
+        let current_state = *tile_states.get(current_entity).unwrap();
+        let size = storages[tile.floor as usize].size;
+
+        // Portals count as adjacent to their partners, and stairs to the stairs right above and below
+        let mut linked = match current_state {
+            TileState::Portal(pair) => self
+                .portal_partners(PathfinderTile::zero(tile.pos, tile.floor), pair)
+                .collect(),
+            _ => Vec::new(),
+        };
+        if current_state == TileState::Stairs {
+            linked.extend(
+                [tile.floor.checked_sub(1), tile.floor.checked_add(1)]
+                    .into_iter()
+                    .flatten()
+                    .map(|floor| PathfinderTile::zero(tile.pos, floor))
+                    .filter(|other| {
+                        tile_entity(storages, *other).is_some_and(|entity| {
+                            *tile_states.get(entity).unwrap() == TileState::Stairs
+                        })
+                    }),
+            );
+        }
+
+        let linked_neighbors: Vec<(Option<Cardinal>, Option<PathfinderTile>)> = linked
+            .into_iter()
+            .map(|other| {
+                let neighbor = PathfinderTile {
+                    keys: tile.keys,
+                    floor: other.floor,
+                    ..PathfinderTile::new(other.pos, &self.goal_tiles, self.topology, size)
+                };
+                (None, Some(neighbor))
+            })
+            .collect();
+
+        for (direction, neighbor) in Cardinal::ALL
+            .map(Some)
+            .into_iter()
+            .zip(tile.neighbors(&self.goal_tiles, self.topology, size))
+            .chain(linked_neighbors)
+        {
+            // Off the edge of the map, and that edge doesn't wrap
+            let Some(mut neighbor) = neighbor else {
+                debug!("neighbor edge of {}", tile);
+                self.trace.neighbor(None, NeighborOutcome::Bounds);
                 continue;
-            }
-            self.visited.insert(neighbor);
+            };
 
             // Get corresponding tile entity to do bookkeeping
-            let Some(entity) = storage.checked_get(&neighbor.pos) else {
+            let Some(entity) = tile_entity(storages, neighbor) else {
                 debug!("neighbor bounds {}", neighbor);
+                self.trace.neighbor(Some(neighbor), NeighborOutcome::Bounds);
                 continue;
             };
 
+            let next_state = *tile_states.get(entity).unwrap();
+
+            // Checked before marking as visited, since the neighbor might still be reached from another side
+            if let Some(direction) = direction
+                && !one_way_allows(current_state, next_state, direction)
+            {
+                debug!("neighbor one way {}", neighbor);
+                self.trace.neighbor(Some(neighbor), NeighborOutcome::OneWay);
+                continue;
+            }
+
+            // Stepping onto a key picks it up, moving into the next layer of the search
+            if let TileState::Key(color) = next_state {
+                neighbor.keys |= color.bit();
+            }
+
+            // Doors are solid until their key is held
+            if let TileState::Door(color) = next_state
+                && neighbor.keys & color.bit() == 0
+            {
+                debug!("neighbor locked {}", neighbor);
+                self.trace.neighbor(Some(neighbor), NeighborOutcome::Locked);
+                continue;
+            }
+
+            let jump = match direction {
+                Some(_) => {
+                    tile.pos.x.abs_diff(neighbor.pos.x) + tile.pos.y.abs_diff(neighbor.pos.y) != 1
+                }
+                None => true,
+            };
+            let parent = self.pick_parent(tile, &mut neighbor, jump, storages, &tile_states);
+
+            // Cost ordered searches can requeue a tile that's still waiting if they found a cheaper way to it
+            let cheaper = self.option.cost_ordered()
+                && !self.closed.contains(&neighbor)
+                && self
+                    .costs
+                    .get(&neighbor)
+                    .is_some_and(|&cost| neighbor.cost < cost);
+
+            // Don't requeue tiles we've already visited
+            if self.visited.contains(&neighbor) && !cheaper {
+                debug!("neighbor skip {}", neighbor);
+                self.trace
+                    .neighbor(Some(neighbor), NeighborOutcome::AlreadyVisited);
+                continue;
+            }
+            self.visited.insert(neighbor);
+
             let mut neighbor_state = tile_states.get_mut(entity).unwrap();
 
             if *neighbor_state == TileState::Wall {
                 debug!("neighbor wall {}", neighbor);
+                self.trace.neighbor(Some(neighbor), NeighborOutcome::Wall);
                 continue;
             }
 
-            *tile_parents.get_mut(entity).unwrap() = TileParent(Some(tile.pos));
+            self.parents.insert(neighbor, parent);
+            self.costs.insert(neighbor, neighbor.cost);
+            if jump {
+                self.jumps.insert(neighbor);
+            } else {
+                self.jumps.remove(&neighbor);
+            }
+            if self.option == AlgorithmOption::LazyThetaStar && parent != tile {
+                self.fallbacks.insert(neighbor, tile);
+            } else {
+                self.fallbacks.remove(&neighbor);
+            }
+            // Stairs parents are on another floor, which TileParent can't point to
+            *tile_parents.get_mut(entity).unwrap() =
+                TileParent((parent.floor == neighbor.floor).then_some(parent.pos));
 
             // Finally enqueue the neighbor tile
             debug!("neighbor queue {}", neighbor);
+            self.trace.neighbor(Some(neighbor), NeighborOutcome::Queued);
             self.algorithm.insert(neighbor);
+            self.note_queued(neighbor);
 
-            if *neighbor_state == TileState::Empty {
+            // Tiles already seen in another layer get queued again, so each layer's search shows up
+            if matches!(*neighbor_state, TileState::Empty | TileState::Visited(_)) {
                 *neighbor_state = TileState::Queued(neighbor.distance);
             }
         }
 
         // Finally finish bookkeeping on now visited tile
-        let mut tile_state = tile_states
-            .get_mut(storage.checked_get(&tile.pos).unwrap())
-            .unwrap();
+        let mut tile_state = tile_states.get_mut(current_entity).unwrap();
 
         if let TileState::Queued(distance) = *tile_state {
             *tile_state = TileState::Visited(distance);
@@ -209,15 +611,235 @@ impl Pathfinder {
     }
 }
 
+impl Pathfinder {
+    /**
+     * Where one move from a tile leads on its own floor, by the same rules the search follows: edges wrap with the
+     * topology, portals lead to their partners, one way tiles are respected, and doors only open with their key, which
+     * stepping onto a key picks up
+     * Tile states come from state_at rather than the map, so callers can see through whatever is covering a tile
+     */
+    pub fn moves(
+        &self,
+        pos: TilePos,
+        floor: u32,
+        keys: u8,
+        size: TilemapSize,
+        state_at: impl Fn(TilePos) -> TileState,
+    ) -> Vec<(TilePos, u8)> {
+        let current = state_at(pos);
+
+        let stepped = Cardinal::ALL.into_iter().filter_map(|direction| {
+            let (dx, dy) = direction.offset();
+            let next = TilePos::new(
+                offset(pos.x, dx, size.x, self.topology.wraps_x())?,
+                offset(pos.y, dy, size.y, self.topology.wraps_y())?,
+            );
+            one_way_allows(current, state_at(next), direction).then_some(next)
+        });
+        let partners: Vec<TilePos> = match current {
+            TileState::Portal(pair) => self
+                .portal_partners(PathfinderTile::zero(pos, floor), pair)
+                .filter(|other| other.floor == floor)
+                .map(|other| other.pos)
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        stepped
+            .chain(partners)
+            .filter_map(|next| match state_at(next) {
+                TileState::Wall => None,
+                TileState::Door(color) if keys & color.bit() == 0 => None,
+                TileState::Key(color) => Some((next, keys | color.bit())),
+                _ => Some((next, keys)),
+            })
+            .collect()
+    }
+
+    /**
+     * What stepping onto a tile costs, from the terrain under it
+     */
+    pub fn step_cost(&self, tile: PathfinderTile) -> f32 {
+        self.terrain
+            .get(&PathfinderTile::zero(tile.pos, tile.floor))
+            .copied()
+            .unwrap_or_default()
+            .cost() as f32
+    }
+
+    /**
+     * What walking a straight line costs, the length scaled by the average terrain cost of the tiles it enters
+     */
+    fn line_cost(&self, from: PathfinderTile, to: PathfinderTile) -> f32 {
+        let tiles = line(from.pos, to.pos);
+        if tiles.is_empty() {
+            return 0.0;
+        }
+
+        let total: f32 = tiles
+            .iter()
+            .map(|&pos| self.step_cost(PathfinderTile::zero(pos, to.floor)))
+            .sum();
+        tile_distance(from.pos, to.pos) * total / tiles.len() as f32
+    }
+
+    fn note_queued(&mut self, tile: PathfinderTile) {
+        let step = self.step;
+        let key = PathfinderTile::zero(tile.pos, tile.floor);
+        let record = self.records.entry(key).or_default();
+
+        record.queued = Some(step);
+        if record.cost.is_none_or(|cost| tile.cost < cost) {
+            record.cost = Some(tile.cost);
+            record.distance = tile.distance;
+        }
+        self.touched.push(key);
+    }
+
+    /**
+     * Which tile a neighbor should hang off of, filling in its cost
+     * Any angle searches skip straight to the grandparent when that's no more expensive, and for Theta* visible
+     */
+    fn pick_parent(
+        &self,
+        tile: PathfinderTile,
+        neighbor: &mut PathfinderTile,
+        jump: bool,
+        storages: &[&TileStorage],
+        tile_states: &Query<&mut TileState>,
+    ) -> PathfinderTile {
+        neighbor.cost = tile.cost + self.step_cost(*neighbor);
+
+        if !self.option.any_angle() || jump {
+            return tile;
+        }
+
+        let Some(&grandparent) = self.parents.get(&tile) else {
+            return tile;
+        };
+
+        if grandparent.floor != neighbor.floor || grandparent.keys != neighbor.keys {
+            return tile;
+        }
+
+        let cost = self.costs[&grandparent] + self.line_cost(grandparent, *neighbor);
+        if cost > neighbor.cost {
+            return tile;
+        }
+
+        // Lazy Theta* puts off the line of sight check until the neighbor is stepped on
+        if self.option != AlgorithmOption::LazyThetaStar
+            && !visible(storages, tile_states, grandparent, *neighbor)
+        {
+            return tile;
+        }
+
+        neighbor.cost = cost;
+        grandparent
+    }
+
+    /**
+     * Lazy Theta* assumes a tile's grandparent is in sight when queueing it, once stepped on that gets checked,
+     * going back to the neighbor that queued it if not
+     */
+    fn check_lazy_parent(
+        &mut self,
+        mut tile: PathfinderTile,
+        storages: &[&TileStorage],
+        tile_states: &Query<&mut TileState>,
+        mut tile_parents: Query<&mut TileParent>,
+    ) -> PathfinderTile {
+        let Some(fallback) = self.fallbacks.remove(&tile) else {
+            return tile;
+        };
+
+        if self.parents.get(&tile).is_none_or(|&parent| {
+            parent == fallback || visible(storages, tile_states, parent, tile)
+        }) {
+            return tile;
+        }
+
+        debug!("lazy parent out of sight for {}, using {}", tile, fallback);
+        tile.cost = self.costs[&fallback] + self.step_cost(tile);
+        self.costs.insert(tile, tile.cost);
+        self.parents.insert(tile, fallback);
+
+        let entity = tile_entity(storages, tile).unwrap();
+        *tile_parents.get_mut(entity).unwrap() =
+            TileParent((fallback.floor == tile.floor).then_some(fallback.pos));
+
+        tile
+    }
+}
+
+/**
+ * Whether a straight line can be walked between two tiles on the same floor
+ */
+fn visible(
+    storages: &[&TileStorage],
+    tile_states: &Query<&mut TileState>,
+    from: PathfinderTile,
+    to: PathfinderTile,
+) -> bool {
+    from.floor == to.floor
+        && line_of_sight(from.pos, to.pos, |pos| {
+            let entity = storages[from.floor as usize].checked_get(&pos).unwrap();
+            blocks_sight(*tile_states.get(entity).unwrap())
+        })
+}
+
+/**
+ * Look up a tile's entity on whichever floor it's on
+ */
+fn tile_entity(storages: &[&TileStorage], tile: PathfinderTile) -> Option<Entity> {
+    storages.get(tile.floor as usize)?.checked_get(&tile.pos)
+}
+
+/**
+ * One way tiles can only be left in the direction they point, and can't be entered against it
+ */
+fn one_way_allows(from: TileState, to: TileState, direction: Cardinal) -> bool {
+    if let TileState::OneWay(from_direction) = from
+        && from_direction != direction
+    {
+        return false;
+    }
+
+    if let TileState::OneWay(to_direction) = to
+        && to_direction == direction.opposite()
+    {
+        return false;
+    }
+
+    true
+}
+
 impl Default for Pathfinder {
     fn default() -> Self {
         Self {
+            option: AlgorithmOption::default(),
             algorithm: AlgorithmOption::default().into(),
             visited: Default::default(),
+            closed: Default::default(),
+            parents: Default::default(),
+            costs: Default::default(),
+            jumps: Default::default(),
+            fallbacks: Default::default(),
+            records: Default::default(),
+            touched: Default::default(),
+            searches: 0,
+            topology: Default::default(),
             start_tiles: Default::default(),
             goal_tiles: Default::default(),
+            portals: Default::default(),
+            terrain: Default::default(),
+            endpoints_moved: Default::default(),
+            live: Default::default(),
             step: Default::default(),
             complete: Default::default(),
+            trace: Trace::new(AlgorithmOption::default(), Topology::default()),
+            layer: Default::default(),
+            path: Default::default(),
         }
     }
 }
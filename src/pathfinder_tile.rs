@@ -1,61 +1,222 @@
 use std::{cmp::Reverse, collections::HashSet, fmt::Display, hash::Hash};
 
-use bevy_ecs_tilemap::tiles::TilePos;
+use bevy_ecs_tilemap::{map::TilemapSize, tiles::TilePos};
+
+/**
+ * Which edges of the map wrap around to the opposite side
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Topology {
+    #[default]
+    Bounded,
+    WrapHorizontal,
+    WrapVertical,
+    Torus,
+}
+
+impl Topology {
+    pub fn wraps_x(self) -> bool {
+        matches!(self, Topology::WrapHorizontal | Topology::Torus)
+    }
+
+    pub fn wraps_y(self) -> bool {
+        matches!(self, Topology::WrapVertical | Topology::Torus)
+    }
+
+    /**
+     * Name of the heuristic used with this topology, recorded in exported traces
+     */
+    pub fn heuristic(self) -> &'static str {
+        match self {
+            Topology::Bounded => "euclidean",
+            _ => "euclidean (wrapped)",
+        }
+    }
+}
+
+/**
+ * The four directions a tile can be left in, also used to point one way tiles
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cardinal {
+    North,
+    East,
+    South,
+    West,
+}
+
+impl Cardinal {
+    // Same order neighbors are generated in
+    pub const ALL: [Cardinal; 4] = [
+        Cardinal::East,
+        Cardinal::West,
+        Cardinal::North,
+        Cardinal::South,
+    ];
+
+    pub fn offset(self) -> (i32, i32) {
+        match self {
+            Cardinal::North => (0, 1),
+            Cardinal::East => (1, 0),
+            Cardinal::South => (0, -1),
+            Cardinal::West => (-1, 0),
+        }
+    }
+
+    pub fn opposite(self) -> Self {
+        match self {
+            Cardinal::North => Cardinal::South,
+            Cardinal::East => Cardinal::West,
+            Cardinal::South => Cardinal::North,
+            Cardinal::West => Cardinal::East,
+        }
+    }
+}
+
+/**
+ * Colors of key and door tiles, a door only opens once its key is held
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyColor {
+    Yellow,
+    Cyan,
+    Magenta,
+}
+
+impl KeyColor {
+    pub const ALL: [KeyColor; 3] = [KeyColor::Yellow, KeyColor::Cyan, KeyColor::Magenta];
+
+    /**
+     * This key's bit in a PathfinderTile's inventory
+     */
+    pub fn bit(self) -> u8 {
+        match self {
+            KeyColor::Yellow => 0b001,
+            KeyColor::Cyan => 0b010,
+            KeyColor::Magenta => 0b100,
+        }
+    }
+}
+
+/**
+ * Move a coordinate by one, returning None when falling off an edge that doesn't wrap
+ */
+pub fn offset(value: u32, delta: i32, size: u32, wraps: bool) -> Option<u32> {
+    let moved = value as i64 + delta as i64;
+    if wraps {
+        Some(moved.rem_euclid(size as i64) as u32)
+    } else if (0..size as i64).contains(&moved) {
+        Some(moved as u32)
+    } else {
+        None
+    }
+}
+
+/**
+ * Distance along one axis, going around the back if that's shorter
+ */
+fn axis_distance(a: u32, b: u32, size: u32, wraps: bool) -> i32 {
+    let direct = (a as i32 - b as i32).abs();
+    if wraps {
+        direct.min(size as i32 - direct)
+    } else {
+        direct
+    }
+}
 
 #[derive(Debug, Clone, Copy, Default)]
 pub struct PathfinderTile {
     pub pos: TilePos,
     pub distance: u32,
+    // Bitset of KeyColors collected on the way here, each combination is its own layer of the search
+    pub keys: u8,
+    pub floor: u32,
+    // Cost of the best known way here from the start, grid steps cost 1 and any angle segments their length
+    pub cost: f32,
 }
 
 impl PathfinderTile {
     /**
      * Create a new tile, finding distance from a goal list
      */
-    pub fn new(pos: TilePos, goals: &HashSet<PathfinderTile>) -> Self {
+    pub fn new(
+        pos: TilePos,
+        goals: &HashSet<PathfinderTile>,
+        topology: Topology,
+        size: TilemapSize,
+    ) -> Self {
         // Find the distance to the closest goal
         let distance = goals
             .iter()
             .copied()
-            .map(|goal| distance(pos, goal.pos))
+            .map(|goal| distance(pos, goal.pos, topology, size))
             .min()
             .unwrap_or(0);
 
-        Self { pos, distance }
+        Self {
+            pos,
+            distance,
+            keys: 0,
+            floor: 0,
+            cost: 0.0,
+        }
     }
 
     /**
-     * Create a new tile with 0 distance, used for equality comparisons
+     * Create a new tile with 0 distance and no keys, used for equality comparisons
      */
-    pub fn zero(pos: TilePos) -> Self {
-        Self { pos, distance: 0 }
+    pub fn zero(pos: TilePos, floor: u32) -> Self {
+        Self {
+            pos,
+            distance: 0,
+            keys: 0,
+            floor,
+            cost: 0.0,
+        }
     }
 
     /**
-     * Compute a list of all direct neighbors of this tile, finding distances from a goal list
+     * Compute a list of all direct neighbors of this tile on the same floor, finding distances from a goal list and carrying over keys
+     * Neighbors are in the order of Cardinal::ALL, and off the edge of the map are None unless that edge wraps around
      */
-    pub fn neighbors(&self, goals: &HashSet<PathfinderTile>) -> [PathfinderTile; 4] {
+    pub fn neighbors(
+        &self,
+        goals: &HashSet<PathfinderTile>,
+        topology: Topology,
+        size: TilemapSize,
+    ) -> [Option<PathfinderTile>; 4] {
         let TilePos { x, y } = self.pos;
-        [
-            PathfinderTile::new(TilePos::new(x.saturating_add(1), y), goals),
-            PathfinderTile::new(TilePos::new(x.saturating_sub(1), y), goals),
-            PathfinderTile::new(TilePos::new(x, y.saturating_add(1)), goals),
-            PathfinderTile::new(TilePos::new(x, y.saturating_sub(1)), goals),
-        ]
+        Cardinal::ALL.map(|direction| {
+            let (dx, dy) = direction.offset();
+            let x = offset(x, dx, size.x, topology.wraps_x())?;
+            let y = offset(y, dy, size.y, topology.wraps_y())?;
+            Some(PathfinderTile {
+                keys: self.keys,
+                floor: self.floor,
+                ..PathfinderTile::new(TilePos::new(x, y), goals, topology, size)
+            })
+        })
+    }
+
+    /**
+     * Estimated total cost of a path through this tile, which A* and the any angle searches order by
+     */
+    pub fn score(&self) -> f32 {
+        self.cost + self.distance as f32
     }
 }
 
-// Good ol' pythagorean theorem
-fn distance(a: TilePos, b: TilePos) -> u32 {
-    let x_diff = b.x as i32 - a.x as i32;
-    let y_diff = b.y as i32 - a.y as i32;
+// Good ol' pythagorean theorem, taking the shortest way around any wrapping edges
+fn distance(a: TilePos, b: TilePos, topology: Topology, size: TilemapSize) -> u32 {
+    let x_diff = axis_distance(a.x, b.x, size.x, topology.wraps_x());
+    let y_diff = axis_distance(a.y, b.y, size.y, topology.wraps_y());
     (x_diff.pow(2) + y_diff.pow(2)).isqrt().unsigned_abs()
 }
 
-// We only care about position when doing equality, distance is ignored
+// We only care about position, floor and keys when doing equality, distance and cost are ignored
 impl PartialEq for PathfinderTile {
     fn eq(&self, other: &Self) -> bool {
-        self.pos.eq(&other.pos)
+        self.pos.eq(&other.pos) && self.floor == other.floor && self.keys == other.keys
     }
 }
 
@@ -64,6 +225,8 @@ impl Eq for PathfinderTile {}
 impl Hash for PathfinderTile {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.pos.hash(state);
+        self.floor.hash(state);
+        self.keys.hash(state);
     }
 }
 
@@ -82,6 +245,13 @@ impl Ord for PathfinderTile {
 
 impl Display for PathfinderTile {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "({}, {})", self.pos.x, self.pos.y)
+        write!(f, "({}, {})", self.pos.x, self.pos.y)?;
+        if self.floor != 0 {
+            write!(f, " floor {}", self.floor)?;
+        }
+        if self.keys != 0 {
+            write!(f, " keys {:03b}", self.keys)?;
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,610 @@
+use std::collections::{HashMap, HashSet};
+
+use bevy::{color::palettes::basic, prelude::*};
+use bevy_ecs_tilemap::prelude::*;
+use bevy_egui::input::egui_wants_any_input;
+use rand::{rng, seq::IndexedRandom};
+
+use crate::{
+    ActiveFloor, CurrentFloor, Floor, MAP_SIZE, TileState,
+    algorithm::Algorithm,
+    floor_storages,
+    pathfinder::Pathfinder,
+    pathfinder_tile::{Cardinal, PathfinderTile},
+    smoothing::blocks_sight,
+    tile_center,
+};
+
+pub fn pursuit_plugin(app: &mut App) {
+    app.init_resource::<Pursuit>()
+        .add_systems(FixedUpdate, (watch_obstacles, pursue).chain())
+        .add_systems(
+            Update,
+            (steer_goals.run_if(not(egui_wants_any_input)), draw_pursuit),
+        );
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GoalMovement {
+    #[default]
+    RandomWalk,
+    // Each goal walks in a straight line and turns around at walls
+    Patrol,
+    // Arrow keys move every goal at once
+    Keyboard,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Replanning {
+    // Search from the chaser to the closest goal from scratch every time the goal moves
+    #[default]
+    Restart,
+    // Stick with the same goal, cutting the old path short when the goal steps onto it, and searching from the
+    // chaser only to the goal, or only back onto the old path past a wall
+    Incremental,
+}
+
+impl Replanning {
+    pub fn name(self) -> &'static str {
+        match self {
+            Replanning::Restart => "restart",
+            Replanning::Incremental => "incremental",
+        }
+    }
+}
+
+/**
+ * How a finished pursuit went, kept around to compare replanning strategies
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct PursuitResult {
+    pub replanning: Replanning,
+    pub expansions: usize,
+    pub replans: usize,
+    pub steps: usize,
+    pub detours: usize,
+}
+
+/**
+ * Goals that move around while the start tile chases them, replanning as they go
+ */
+#[derive(Debug, Resource)]
+pub struct Pursuit {
+    pub movement: GoalMovement,
+    pub replanning: Replanning,
+    // Fixed update ticks between each move of the chaser and of the goals
+    pub chaser_every: usize,
+    pub goal_every: usize,
+
+    running: bool,
+    tick: usize,
+    // The chase stays on the floor it started on, whichever one is being looked at
+    floor: u32,
+    chaser: TilePos,
+    // Keys the chaser has picked up, and the tile it's standing on so it can be put back once it moves on
+    keys: u8,
+    under: TileState,
+    goals: Vec<(TilePos, Cardinal)>,
+    // Which goal the path leads to, and the path itself starting at the chaser, with the keys held on each tile
+    target: usize,
+    path: Vec<(TilePos, u8)>,
+    steer: Option<Cardinal>,
+    // Earliest index along the path that a wall has appeared on since the last move
+    blocked: Option<usize>,
+
+    expansions: usize,
+    replans: usize,
+    steps: usize,
+    detours: usize,
+    pub results: Vec<PursuitResult>,
+}
+
+impl Default for Pursuit {
+    fn default() -> Self {
+        Self {
+            movement: GoalMovement::default(),
+            replanning: Replanning::default(),
+            chaser_every: 8,
+            goal_every: 16,
+
+            running: false,
+            tick: 0,
+            floor: 0,
+            chaser: TilePos::new(0, 0),
+            keys: 0,
+            under: TileState::Empty,
+            goals: Vec::new(),
+            target: 0,
+            path: Vec::new(),
+            steer: None,
+            blocked: None,
+
+            expansions: 0,
+            replans: 0,
+            steps: 0,
+            detours: 0,
+            results: Vec::new(),
+        }
+    }
+}
+
+impl Pursuit {
+    pub fn running(&self) -> bool {
+        self.running
+    }
+
+    pub fn expansions(&self) -> usize {
+        self.expansions
+    }
+
+    /**
+     * The chaser's current plan, starting at the chaser and ending at its target goal
+     */
+    pub fn path(&self) -> Vec<TilePos> {
+        self.path.iter().map(|&(pos, _)| pos).collect()
+    }
+
+    pub fn floor(&self) -> u32 {
+        self.floor
+    }
+
+    /**
+     * Start chasing from the start tile on a floor, returns false without a start and a goal
+     */
+    pub fn begin(
+        &mut self,
+        tile_states: Query<&mut TileState>,
+        storage: &TileStorage,
+        floor: u32,
+    ) -> bool {
+        let state = |pos: TilePos| *tile_states.get(storage.checked_get(&pos).unwrap()).unwrap();
+        let positions = (0..MAP_SIZE).flat_map(|y| (0..MAP_SIZE).map(move |x| TilePos::new(x, y)));
+
+        let Some(chaser) = positions
+            .clone()
+            .find(|&pos| state(pos) == TileState::Start)
+        else {
+            return false;
+        };
+        self.goals = positions
+            .filter(|&pos| state(pos) == TileState::Goal)
+            .map(|pos| (pos, Cardinal::East))
+            .collect();
+        if self.goals.is_empty() {
+            return false;
+        }
+
+        self.running = true;
+        self.tick = 0;
+        self.floor = floor;
+        self.chaser = chaser;
+        self.keys = 0;
+        self.under = TileState::Empty;
+        self.path.clear();
+        self.steer = None;
+        self.blocked = None;
+        self.expansions = 0;
+        self.replans = 0;
+        self.steps = 0;
+        self.detours = 0;
+        true
+    }
+
+    pub fn stop(&mut self) {
+        self.running = false;
+        self.path.clear();
+    }
+
+    /**
+     * Plan from scratch to whichever goal the search reaches first
+     */
+    fn restart(&mut self, planner: &Planner<impl Fn(TilePos) -> TileState>) {
+        let goals: Vec<TilePos> = self.goals.iter().map(|&(pos, _)| pos).collect();
+        let (path, expansions) = planner.search((self.chaser, self.keys), &goals, |pos, _| {
+            goals.contains(&pos)
+        });
+        self.expansions += expansions;
+
+        let path = path.unwrap_or_default();
+        self.target = path
+            .last()
+            .and_then(|&(end, _)| goals.iter().position(|&goal| goal == end))
+            .unwrap_or(0);
+        self.path = path;
+    }
+
+    /**
+     * Follow the target to where it went, cutting the path short if it stepped onto it, or searching from the chaser
+     * to its new spot, planning from scratch if it can't be reached anymore
+     */
+    fn follow(&mut self, planner: &Planner<impl Fn(TilePos) -> TileState>) {
+        let goal = self.goals[self.target].0;
+
+        if let Some(index) = self.path.iter().position(|&(pos, _)| pos == goal) {
+            self.path.truncate(index + 1);
+            return;
+        }
+
+        let (path, expansions) =
+            planner.search((self.chaser, self.keys), &[goal], |pos, _| pos == goal);
+        self.expansions += expansions;
+        match path {
+            Some(path) => self.path = path,
+            None => self.restart(planner),
+        }
+    }
+
+    /**
+     * Get around a wall that landed on the path, when replanning incrementally by searching from the chaser back onto
+     * the old path past the wall and keeping the rest of it, and searching from scratch otherwise
+     */
+    fn repair(&mut self, blocked: usize, planner: &Planner<impl Fn(TilePos) -> TileState>) {
+        self.detours += 1;
+
+        if self.replanning == Replanning::Restart || blocked + 1 >= self.path.len() {
+            return self.restart(planner);
+        }
+
+        // Rejoining needs the same keys held, otherwise the rest of the path might lead through a locked door
+        let rest: HashMap<(TilePos, u8), usize> = self.path[blocked + 1..]
+            .iter()
+            .enumerate()
+            .map(|(index, &tile)| (tile, blocked + 1 + index))
+            .collect();
+        let targets: Vec<TilePos> = rest.keys().map(|&(pos, _)| pos).collect();
+
+        let (detour, expansions) =
+            planner.search((self.chaser, self.keys), &targets, |pos, keys| {
+                rest.contains_key(&(pos, keys))
+            });
+        self.expansions += expansions;
+
+        let Some(mut detour) = detour else {
+            return self.restart(planner);
+        };
+        let rejoin = rest[detour.last().unwrap()];
+        detour.extend(&self.path[rejoin + 1..]);
+        self.path = detour;
+    }
+
+    /**
+     * Move goals one tile onto open ground, returns whether any of them moved
+     */
+    fn move_goals(
+        &mut self,
+        tile_states: &mut Query<&mut TileState>,
+        storage: &TileStorage,
+    ) -> bool {
+        let steer = self.steer.take();
+        let mut moved = false;
+
+        for index in 0..self.goals.len() {
+            let (pos, heading) = self.goals[index];
+            let free = |direction: Cardinal| {
+                let next = step(pos, direction)?;
+                let state = *tile_states
+                    .get(storage.checked_get(&next).unwrap())
+                    .unwrap();
+                let open = matches!(
+                    state,
+                    TileState::Empty
+                        | TileState::Queued(_)
+                        | TileState::Visited(_)
+                        | TileState::Final(_)
+                );
+                open.then_some((next, direction))
+            };
+
+            let next = match self.movement {
+                GoalMovement::RandomWalk => Cardinal::ALL
+                    .into_iter()
+                    .filter_map(free)
+                    .collect::<Vec<_>>()
+                    .choose(&mut rng())
+                    .copied(),
+                GoalMovement::Patrol => free(heading).or_else(|| free(heading.opposite())),
+                GoalMovement::Keyboard => steer.and_then(free),
+            };
+
+            let Some((next, heading)) = next else {
+                continue;
+            };
+
+            *tile_states
+                .get_mut(storage.checked_get(&pos).unwrap())
+                .unwrap() = TileState::Empty;
+            *tile_states
+                .get_mut(storage.checked_get(&next).unwrap())
+                .unwrap() = TileState::Goal;
+            self.goals[index] = (next, heading);
+            moved = true;
+        }
+
+        moved
+    }
+}
+
+fn step(pos: TilePos, direction: Cardinal) -> Option<TilePos> {
+    let (dx, dy) = direction.offset();
+    let (x, y) = (pos.x as i32 + dx, pos.y as i32 + dy);
+    ((0..MAP_SIZE as i32).contains(&x) && (0..MAP_SIZE as i32).contains(&y))
+        .then(|| TilePos::new(x as u32, y as u32))
+}
+
+/**
+ * What a search during the chase moves by, the same rules and terrain costs as the pathfinder, on the chase's floor
+ */
+struct Planner<'a, F> {
+    pathfinder: &'a Pathfinder,
+    floor: u32,
+    size: TilemapSize,
+    state_at: F,
+}
+
+impl<F: Fn(TilePos) -> TileState> Planner<'_, F> {
+    fn neighbors(&self, pos: TilePos, keys: u8) -> Vec<(TilePos, u8)> {
+        self.pathfinder
+            .moves(pos, self.floor, keys, self.size, &self.state_at)
+    }
+
+    /**
+     * Search with the pathfinder's selected algorithm, with each combination of keys held as its own layer, until
+     * reached accepts a tile, also reporting how many tiles were expanded
+     * Only the targets guide the distance guess, so they should be every tile reached could accept
+     */
+    fn search(
+        &self,
+        from: (TilePos, u8),
+        targets: &[TilePos],
+        reached: impl Fn(TilePos, u8) -> bool,
+    ) -> (Option<Vec<(TilePos, u8)>>, usize) {
+        let option = self.pathfinder.option();
+        let goals: HashSet<PathfinderTile> = targets
+            .iter()
+            .map(|&pos| PathfinderTile::zero(pos, self.floor))
+            .collect();
+        let tile = |(pos, keys): (TilePos, u8), cost: f32| PathfinderTile {
+            keys,
+            floor: self.floor,
+            cost,
+            ..PathfinderTile::new(pos, &goals, self.pathfinder.topology, self.size)
+        };
+
+        let mut algorithm: Box<dyn Algorithm + Send + Sync> = option.into();
+        let mut costs: HashMap<(TilePos, u8), f32> = HashMap::from([(from, 0.0)]);
+        let mut parents: HashMap<(TilePos, u8), (TilePos, u8)> = HashMap::new();
+        let mut closed: HashSet<(TilePos, u8)> = HashSet::new();
+        let mut expansions = 0;
+        algorithm.insert(tile(from, 0.0));
+
+        while let Some(next) = algorithm.next() {
+            let current = (next.pos, next.keys);
+            if !closed.insert(current) {
+                continue;
+            }
+            expansions += 1;
+
+            if reached(current.0, current.1) {
+                let mut path = vec![current];
+                while let Some(&parent) = parents.get(path.last().unwrap()) {
+                    path.push(parent);
+                }
+                path.reverse();
+                return (Some(path), expansions);
+            }
+
+            for neighbor in self.neighbors(current.0, current.1) {
+                let cost = next.cost
+                    + self
+                        .pathfinder
+                        .step_cost(PathfinderTile::zero(neighbor.0, self.floor));
+
+                // Same as the pathfinder, only cost ordered searches come back to a waiting tile for a cheaper way
+                let cheaper = option.cost_ordered()
+                    && !closed.contains(&neighbor)
+                    && costs.get(&neighbor).is_some_and(|&old| cost < old);
+                if costs.contains_key(&neighbor) && !cheaper {
+                    continue;
+                }
+
+                costs.insert(neighbor, cost);
+                parents.insert(neighbor, current);
+                algorithm.insert(tile(neighbor, cost));
+            }
+        }
+
+        (None, expansions)
+    }
+}
+
+/**
+ * System that runs the chase, moving goals and the chaser on their own schedules and replanning whenever a goal moves
+ */
+fn pursue(
+    mut pursuit: ResMut<Pursuit>,
+    pathfinder: Res<Pathfinder>,
+    mut tile_states: Query<&mut TileState>,
+    tilemaps: Query<(&TileStorage, &Floor)>,
+) {
+    if !pursuit.running {
+        return;
+    }
+    pursuit.tick += 1;
+    let storage = floor_storages(&tilemaps)[pursuit.floor as usize];
+
+    let goals_due = match pursuit.movement {
+        GoalMovement::Keyboard => pursuit.steer.is_some(),
+        _ => pursuit.tick.is_multiple_of(pursuit.goal_every.max(1)),
+    };
+    let moved = goals_due && pursuit.move_goals(&mut tile_states, storage);
+
+    // The chaser covers up the tile it's on, which still decides which ways it can leave
+    let (chaser, under) = (pursuit.chaser, pursuit.under);
+    let state_at = |pos: TilePos| {
+        if pos == chaser {
+            under
+        } else {
+            *tile_states.get(storage.checked_get(&pos).unwrap()).unwrap()
+        }
+    };
+    let planner = Planner {
+        pathfinder: &pathfinder,
+        floor: pursuit.floor,
+        size: storage.size,
+        state_at,
+    };
+
+    if let Some(blocked) = pursuit.blocked.take() {
+        debug!("wall appeared {} tiles along the path", blocked);
+        pursuit.replans += 1;
+        pursuit.repair(blocked, &planner);
+    }
+
+    if moved || pursuit.tick == 1 {
+        pursuit.replans += 1;
+        match pursuit.replanning {
+            Replanning::Restart => pursuit.restart(&planner),
+            Replanning::Incremental if pursuit.path.is_empty() => pursuit.restart(&planner),
+            Replanning::Incremental => pursuit.follow(&planner),
+        }
+    }
+
+    if !pursuit.tick.is_multiple_of(pursuit.chaser_every.max(1)) {
+        return;
+    }
+
+    // A wall in the way that nobody noticed yet, or a path that got cut off earlier, both get a fresh look from here
+    let reachable = planner.neighbors(pursuit.chaser, pursuit.keys);
+    if pursuit
+        .path
+        .get(1)
+        .is_none_or(|next| !reachable.contains(next))
+    {
+        pursuit.replans += 1;
+        pursuit.repair(1, &planner);
+    }
+    if pursuit.path.len() < 2 {
+        return;
+    }
+
+    let (next, keys) = pursuit.path[1];
+    pursuit.steps += 1;
+
+    // Caught when the next step lands on a goal, which stays where it is
+    let mut next_state = tile_states
+        .get_mut(storage.checked_get(&next).unwrap())
+        .unwrap();
+    if *next_state == TileState::Goal {
+        let result = PursuitResult {
+            replanning: pursuit.replanning,
+            expansions: pursuit.expansions,
+            replans: pursuit.replans,
+            steps: pursuit.steps,
+            detours: pursuit.detours,
+        };
+        info!(
+            "caught a goal after {} steps, {} replans, {} detours and {} expansions using {} replanning",
+            result.steps,
+            result.replans,
+            result.detours,
+            result.expansions,
+            result.replanning.name()
+        );
+        pursuit.results.push(result);
+        pursuit.stop();
+        return;
+    }
+    let under = std::mem::replace(&mut *next_state, TileState::Start);
+
+    // Put back whatever the chaser was standing on, so it can cross portals and keys without wiping them out
+    let left = std::mem::replace(&mut pursuit.under, under);
+    *tile_states
+        .get_mut(storage.checked_get(&chaser).unwrap())
+        .unwrap() = left;
+    pursuit.chaser = next;
+    pursuit.keys = keys;
+    pursuit.path.remove(0);
+}
+
+/**
+ * System that watches for walls appearing on the chaser's path, whether painted or dropped by obstacles, so it can
+ * replan before walking into them
+ */
+fn watch_obstacles(
+    tiles_query: Query<(&TileState, &TilePos, &Floor), Changed<TileState>>,
+    mut pursuit: ResMut<Pursuit>,
+) {
+    if !pursuit.running {
+        return;
+    }
+
+    for (&state, pos, &Floor(floor)) in tiles_query.iter() {
+        if floor != pursuit.floor || !blocks_sight(state) {
+            continue;
+        }
+
+        if let Some(index) = pursuit
+            .path
+            .iter()
+            .position(|(path_pos, _)| path_pos == pos)
+        {
+            pursuit.blocked = Some(pursuit.blocked.map_or(index, |blocked| blocked.min(index)));
+        }
+    }
+}
+
+/**
+ * System that turns arrow key presses into goal moves when they're being steered by hand
+ */
+fn steer_goals(keyboard: Res<ButtonInput<KeyCode>>, mut pursuit: ResMut<Pursuit>) {
+    if !pursuit.running || pursuit.movement != GoalMovement::Keyboard {
+        return;
+    }
+
+    for (key, direction) in [
+        (KeyCode::ArrowUp, Cardinal::North),
+        (KeyCode::ArrowRight, Cardinal::East),
+        (KeyCode::ArrowDown, Cardinal::South),
+        (KeyCode::ArrowLeft, Cardinal::West),
+    ] {
+        if keyboard.just_pressed(key) {
+            pursuit.steer = Some(direction);
+        }
+    }
+}
+
+/**
+ * System that draws the chaser's current plan
+ */
+fn draw_pursuit(
+    mut gizmos: Gizmos,
+    pursuit: Res<Pursuit>,
+    current: Res<CurrentFloor>,
+    tilemap: Single<
+        (
+            &TilemapSize,
+            &TilemapGridSize,
+            &TilemapTileSize,
+            &TilemapType,
+            &TilemapAnchor,
+            &Transform,
+        ),
+        With<ActiveFloor>,
+    >,
+) {
+    if !pursuit.running || pursuit.floor != current.0 {
+        return;
+    }
+
+    // Moves through portals and around wrapping edges jump across the map, so they're left undrawn
+    for pair in pursuit.path.windows(2) {
+        let ((from, _), (to, _)) = (pair[0], pair[1]);
+        if from.x.abs_diff(to.x) + from.y.abs_diff(to.y) == 1 {
+            gizmos.line_2d(
+                tile_center(*tilemap, from),
+                tile_center(*tilemap, to),
+                basic::LIME,
+            );
+        }
+    }
+}
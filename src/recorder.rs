@@ -0,0 +1,121 @@
+use std::fs::File;
+
+use bevy::prelude::*;
+use bevy_ecs_tilemap::tiles::TileStorage;
+use image::{
+    Delay, Frame, ImageResult, RgbaImage,
+    codecs::gif::{GifEncoder, Repeat},
+};
+
+use crate::{
+    TileState, TileTerrain,
+    export::{ExportOverlays, render_map},
+    pathfinder::Pathfinder,
+};
+
+pub fn recorder_plugin(app: &mut App) {
+    app.init_resource::<Recorder>();
+}
+
+// Past this many frames every other one is dropped and frames are taken half as often, so long runs still fit in memory
+const MAX_FRAMES: usize = 256;
+
+/**
+ * Collects a picture of the grid every few pathfinder steps, drawn straight from the tiles so the camera doesn't matter
+ */
+#[derive(Debug, Resource)]
+pub struct Recorder {
+    pub enabled: bool,
+    pub every: usize,
+    pub pixels_per_tile: u32,
+    pub frame_delay_ms: u32,
+
+    frames: Vec<RgbaImage>,
+    last_step: Option<usize>,
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            every: 1,
+            pixels_per_tile: 8,
+            frame_delay_ms: 50,
+
+            frames: Vec::new(),
+            last_step: None,
+        }
+    }
+}
+
+impl Recorder {
+    /**
+     * Call after every Pathfinder::step, captures a frame on every nth step and always on the last one
+     */
+    pub fn capture(
+        &mut self,
+        pathfinder: &Pathfinder,
+        tile_states: Query<&TileState>,
+        tile_terrains: Query<&TileTerrain>,
+        storage: &TileStorage,
+    ) {
+        // Stepping a completed pathfinder does nothing, so don't record duplicate frames
+        if !self.enabled || self.last_step == Some(pathfinder.step) {
+            return;
+        }
+        self.last_step = Some(pathfinder.step);
+
+        if pathfinder.step.is_multiple_of(self.every.max(1)) || pathfinder.complete {
+            self.frames.push(render_map(
+                tile_states,
+                tile_terrains,
+                storage,
+                self.pixels_per_tile,
+                ExportOverlays::ALL,
+            ));
+        }
+
+        if self.frames.len() >= MAX_FRAMES {
+            // Thin out from the end so the final frame is always kept
+            let last = self.frames.len() - 1;
+            let mut index = 0;
+            self.frames.retain(|_| {
+                let keep = (last - index).is_multiple_of(2);
+                index += 1;
+                keep
+            });
+            self.every = self.every.max(1) * 2;
+            debug!(
+                "recorder thinned out to {} frames, now every {} steps",
+                self.frames.len(),
+                self.every
+            );
+        }
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.last_step = None;
+    }
+
+    /**
+     * Write all captured frames out as a looping GIF, using them up so recording starts over afterwards
+     */
+    pub fn save_gif(&mut self, path: &str) -> ImageResult<()> {
+        let frames = std::mem::take(&mut self.frames);
+        self.last_step = None;
+
+        let mut encoder = GifEncoder::new_with_speed(File::create(path)?, 10);
+        encoder.set_repeat(Repeat::Infinite)?;
+        let delay = Delay::from_numer_denom_ms(self.frame_delay_ms, 1);
+        encoder.encode_frames(
+            frames
+                .into_iter()
+                .map(|frame| Frame::from_parts(frame, 0, 0, delay)),
+        )
+    }
+}
@@ -0,0 +1,268 @@
+use bevy::{color::palettes::basic, prelude::*};
+use bevy_ecs_tilemap::prelude::*;
+
+use crate::{
+    ActiveFloor, CurrentFloor, Floor, TileState, floor_storages, pathfinder::Pathfinder,
+    pathfinder_tile::PathfinderTile, tile_center,
+};
+
+pub fn smoothing_plugin(app: &mut App) {
+    app.init_resource::<SmoothedPath>()
+        .add_systems(Update, (smooth_path, draw_smoothed_path).chain());
+}
+
+/**
+ * The found path pulled tight into straight segments wherever there's line of sight between tiles
+ */
+#[derive(Debug, Default, Resource)]
+pub struct SmoothedPath {
+    pub enabled: bool,
+
+    // Each run is a stretch of the path that stays on one floor without teleporting, drawn as its own polyline
+    pub runs: Vec<Vec<PathfinderTile>>,
+
+    // Lengths in tiles, jumps through portals and stairs count as zero
+    pub path_length: f32,
+    pub smoothed_length: f32,
+}
+
+/**
+ * System that redoes the smoothing whenever the found path changes or smoothing is toggled
+ */
+fn smooth_path(
+    mut smoothed: ResMut<SmoothedPath>,
+    mut last_path: Local<Vec<PathfinderTile>>,
+    pathfinder: Res<Pathfinder>,
+    tile_states: Query<&TileState>,
+    tilemaps: Query<(&TileStorage, &Floor)>,
+) {
+    if *last_path == pathfinder.path && !smoothed.is_changed() {
+        return;
+    }
+    last_path.clone_from(&pathfinder.path);
+
+    smoothed.runs.clear();
+    smoothed.path_length = 0.0;
+    smoothed.smoothed_length = 0.0;
+
+    if !smoothed.enabled || pathfinder.path.is_empty() {
+        return;
+    }
+
+    let storages = floor_storages(&tilemaps);
+    let blocked = |tile: PathfinderTile, pos: TilePos| {
+        let entity = storages[tile.floor as usize].checked_get(&pos).unwrap();
+        blocks_sight(*tile_states.get(entity).unwrap())
+    };
+
+    for run in split_runs(&pathfinder.path, |tile| pathfinder.is_jump(tile)) {
+        smoothed.path_length += length(run);
+
+        let waypoints = string_pull(run, |a, b| {
+            line_of_sight(a.pos, b.pos, |pos| blocked(a, pos))
+        });
+        smoothed.smoothed_length += length(&waypoints);
+        smoothed.runs.push(waypoints);
+    }
+
+    debug!(
+        "smoothed path from {:.2} to {:.2} tiles",
+        smoothed.path_length, smoothed.smoothed_length
+    );
+}
+
+/**
+ * Tiles a straight line can't pass through, conservatively including ones that only block some of the time
+ */
+pub fn blocks_sight(state: TileState) -> bool {
+    matches!(
+        state,
+        TileState::Wall | TileState::Door(_) | TileState::OneWay(_)
+    )
+}
+
+/**
+ * Split the path wherever it jumps through a portal, stairs or a wrapping edge, or where a key gets picked up,
+ * since those points have to stay in the smoothed path
+ */
+fn split_runs(
+    path: &[PathfinderTile],
+    is_jump: impl Fn(PathfinderTile) -> bool,
+) -> Vec<&[PathfinderTile]> {
+    let mut runs = Vec::new();
+    let mut start = 0;
+
+    for i in 1..path.len() {
+        let (a, b) = (path[i - 1], path[i]);
+
+        if is_jump(b) {
+            runs.push(&path[start..i]);
+            start = i;
+        } else if a.keys != b.keys {
+            runs.push(&path[start..=i]);
+            start = i;
+        }
+    }
+    runs.push(&path[start..]);
+
+    runs
+}
+
+/**
+ * Greedily keep the farthest tile still visible from the last waypoint, the ends of the run are always kept
+ */
+fn string_pull(
+    run: &[PathfinderTile],
+    visible: impl Fn(PathfinderTile, PathfinderTile) -> bool,
+) -> Vec<PathfinderTile> {
+    let mut waypoints = vec![run[0]];
+    let mut anchor = 0;
+
+    while anchor < run.len() - 1 {
+        // The next tile is always visible, since the run only steps between neighbors
+        let mut next = anchor + 1;
+        for candidate in (anchor + 2)..run.len() {
+            if visible(run[anchor], run[candidate]) {
+                next = candidate;
+            }
+        }
+
+        waypoints.push(run[next]);
+        anchor = next;
+    }
+
+    waypoints
+}
+
+/**
+ * Tiles on the Bresenham line from a to b, leaving out a itself
+ */
+pub fn line(a: TilePos, b: TilePos) -> Vec<TilePos> {
+    let (mut x, mut y) = (a.x as i32, a.y as i32);
+    let (end_x, end_y) = (b.x as i32, b.y as i32);
+
+    let dx = (end_x - x).abs();
+    let dy = -(end_y - y).abs();
+    let step_x = if x < end_x { 1 } else { -1 };
+    let step_y = if y < end_y { 1 } else { -1 };
+    let mut error = dx + dy;
+
+    let mut tiles = Vec::new();
+    while (x, y) != (end_x, end_y) {
+        let doubled = 2 * error;
+        if doubled >= dy {
+            error += dy;
+            x += step_x;
+        }
+        if doubled <= dx {
+            error += dx;
+            y += step_y;
+        }
+        tiles.push(TilePos::new(x as u32, y as u32));
+    }
+
+    tiles
+}
+
+/**
+ * Walk the line between two tiles, failing if any tile on it is blocked, or if a diagonal step cuts the corner of a
+ * blocked tile
+ */
+pub fn line_of_sight(a: TilePos, b: TilePos, blocked: impl Fn(TilePos) -> bool) -> bool {
+    let mut previous = a;
+
+    for pos in line(a, b) {
+        if pos.x != previous.x
+            && pos.y != previous.y
+            && (blocked(TilePos::new(pos.x, previous.y))
+                || blocked(TilePos::new(previous.x, pos.y)))
+        {
+            return false;
+        }
+
+        if blocked(pos) {
+            return false;
+        }
+
+        previous = pos;
+    }
+
+    true
+}
+
+pub fn tile_distance(a: TilePos, b: TilePos) -> f32 {
+    Vec2::new(a.x as f32, a.y as f32).distance(Vec2::new(b.x as f32, b.y as f32))
+}
+
+fn length(run: &[PathfinderTile]) -> f32 {
+    run.windows(2)
+        .map(|pair| tile_distance(pair[0].pos, pair[1].pos))
+        .sum()
+}
+
+/**
+ * System that draws the smoothed path on the current floor as a polyline
+ */
+fn draw_smoothed_path(
+    mut gizmos: Gizmos,
+    smoothed: Res<SmoothedPath>,
+    current: Res<CurrentFloor>,
+    tilemap: Single<
+        (
+            &TilemapSize,
+            &TilemapGridSize,
+            &TilemapTileSize,
+            &TilemapType,
+            &TilemapAnchor,
+            &Transform,
+        ),
+        With<ActiveFloor>,
+    >,
+) {
+    for run in &smoothed.runs {
+        if run[0].floor != current.0 {
+            continue;
+        }
+
+        gizmos.linestrip_2d(
+            run.iter().map(|tile| tile_center(*tilemap, tile.pos)),
+            basic::AQUA,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_of_sight_around_walls() {
+        let open = |_: TilePos| false;
+        assert!(line_of_sight(TilePos::new(0, 0), TilePos::new(7, 3), open));
+
+        let wall = |pos: TilePos| pos == TilePos::new(3, 3);
+        assert!(!line_of_sight(TilePos::new(0, 3), TilePos::new(6, 3), wall));
+        assert!(line_of_sight(TilePos::new(0, 4), TilePos::new(6, 4), wall));
+
+        // A diagonal step can't squeeze past the corner of a wall
+        let corner = |pos: TilePos| pos == TilePos::new(1, 0);
+        assert!(!line_of_sight(
+            TilePos::new(0, 0),
+            TilePos::new(1, 1),
+            corner
+        ));
+    }
+
+    #[test]
+    fn string_pull_keeps_corners() {
+        // An L shaped run around a wall at (1, 1) only needs its ends and the corner
+        let run: Vec<PathfinderTile> = [(0, 0), (1, 0), (2, 0), (2, 1), (2, 2)]
+            .map(|(x, y)| PathfinderTile::zero(TilePos::new(x, y), 0))
+            .into();
+        let wall = |pos: TilePos| pos == TilePos::new(1, 1);
+
+        let waypoints = string_pull(&run, |a, b| line_of_sight(a.pos, b.pos, wall));
+
+        assert_eq!(waypoints, [run[0], run[2], run[4]]);
+    }
+}
@@ -0,0 +1,136 @@
+use std::{fs::File, io::BufWriter};
+
+use serde::Serialize;
+
+use crate::{
+    TileState,
+    algorithm::AlgorithmOption,
+    pathfinder_tile::{Cardinal, KeyColor, PathfinderTile, Topology},
+};
+
+/**
+ * Everything that happened during one pathfinder run, in a shape that's easy to load from a notebook
+ */
+#[derive(Debug, Default, Serialize)]
+pub struct Trace {
+    pub algorithm: &'static str,
+    pub heuristic: &'static str,
+
+    // One grid per floor, rows from top to bottom, `#` wall, `.` empty, `S` start, `E` goal, `P` portal, `^>v<` one way,
+    // `ycm` keys and `YCM` their doors, `H` stairs
+    pub map: Vec<Vec<String>>,
+
+    // Positions are written as [x, y, floor]
+    pub start: Option<[u32; 3]>,
+    pub goals: Vec<[u32; 3]>,
+
+    pub steps: Vec<TraceStep>,
+    pub path: Vec<[u32; 3]>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TraceStep {
+    pub step: usize,
+    pub popped: Option<[u32; 3]>,
+    // Bitset of keys held by the popped tile
+    pub keys: u8,
+    pub reached_goal: bool,
+    pub neighbors: Vec<TraceNeighbor>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TraceNeighbor {
+    // None when the neighbor would be off a non-wrapping edge of the map
+    pub pos: Option<[u32; 3]>,
+    pub outcome: NeighborOutcome,
+}
+
+/**
+ * What the pathfinder decided to do with a neighbor, mirroring the `neighbor ...` debug logs
+ */
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NeighborOutcome {
+    Queued,
+    AlreadyVisited,
+    Bounds,
+    OneWay,
+    Locked,
+    Wall,
+}
+
+impl Trace {
+    pub fn new(algorithm: AlgorithmOption, topology: Topology) -> Self {
+        Self {
+            algorithm: algorithm.name(),
+            heuristic: topology.heuristic(),
+            ..Default::default()
+        }
+    }
+
+    /**
+     * Record a floor of the map as it looked when the search began, given rows of tiles from the bottom up
+     */
+    pub fn push_floor(&mut self, rows: impl Iterator<Item = Vec<TileState>>) {
+        let mut map: Vec<String> = rows
+            .map(|row| {
+                row.into_iter()
+                    .map(|state| match state {
+                        TileState::Wall => '#',
+                        TileState::Start => 'S',
+                        TileState::Goal => 'E',
+                        TileState::Portal(_) => 'P',
+                        TileState::OneWay(Cardinal::North) => '^',
+                        TileState::OneWay(Cardinal::East) => '>',
+                        TileState::OneWay(Cardinal::South) => 'v',
+                        TileState::OneWay(Cardinal::West) => '<',
+                        TileState::Key(KeyColor::Yellow) => 'y',
+                        TileState::Key(KeyColor::Cyan) => 'c',
+                        TileState::Key(KeyColor::Magenta) => 'm',
+                        TileState::Door(KeyColor::Yellow) => 'Y',
+                        TileState::Door(KeyColor::Cyan) => 'C',
+                        TileState::Door(KeyColor::Magenta) => 'M',
+                        TileState::Stairs => 'H',
+                        _ => '.',
+                    })
+                    .collect()
+            })
+            .collect();
+        map.reverse();
+        self.map.push(map);
+    }
+
+    pub fn begin_step(&mut self, step: usize, popped: Option<PathfinderTile>) {
+        self.steps.push(TraceStep {
+            step,
+            popped: popped.map(pos),
+            keys: popped.map_or(0, |popped| popped.keys),
+            reached_goal: false,
+            neighbors: Vec::new(),
+        });
+    }
+
+    pub fn reached_goal(&mut self) {
+        if let Some(step) = self.steps.last_mut() {
+            step.reached_goal = true;
+        }
+    }
+
+    pub fn neighbor(&mut self, neighbor: Option<PathfinderTile>, outcome: NeighborOutcome) {
+        if let Some(step) = self.steps.last_mut() {
+            step.neighbors.push(TraceNeighbor {
+                pos: neighbor.map(pos),
+                outcome,
+            });
+        }
+    }
+
+    pub fn save(&self, path: &str) -> std::io::Result<()> {
+        serde_json::to_writer_pretty(BufWriter::new(File::create(path)?), self)?;
+        Ok(())
+    }
+}
+
+pub fn pos(tile: PathfinderTile) -> [u32; 3] {
+    [tile.pos.x, tile.pos.y, tile.floor]
+}
@@ -0,0 +1,139 @@
+use bevy::{color::palettes::basic, prelude::*};
+use bevy_ecs_tilemap::prelude::*;
+
+use crate::{
+    ActiveFloor, CurrentFloor, pathfinder::Pathfinder, pathfinder_tile::PathfinderTile,
+    smoothing::tile_distance, tile_center,
+};
+
+pub fn walker_plugin(app: &mut App) {
+    app.init_resource::<Walker>()
+        .add_systems(Startup, spawn_walker)
+        .add_systems(Update, walk);
+}
+
+/**
+ * Walks a sprite along the found path once the pathfinder completes, so a run ends with something moving
+ */
+#[derive(Debug, Resource)]
+pub struct Walker {
+    pub enabled: bool,
+    // Tiles per second
+    pub speed: f32,
+
+    route: Vec<PathfinderTile>,
+    // Which stretch of the route the sprite is on, and how many tiles along it
+    segment: usize,
+    progress: f32,
+}
+
+impl Default for Walker {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            speed: 6.0,
+
+            route: Vec::new(),
+            segment: 0,
+            progress: 0.0,
+        }
+    }
+}
+
+impl Walker {
+    /**
+     * Send the sprite back to the start of the path
+     */
+    pub fn restart(&mut self) {
+        self.segment = 0;
+        self.progress = 0.0;
+    }
+}
+
+#[derive(Debug, Component)]
+struct WalkerSprite;
+
+fn spawn_walker(mut commands: Commands) {
+    commands.spawn((
+        WalkerSprite,
+        Sprite::from_color(basic::FUCHSIA, Vec2::splat(10.0)),
+        // Drawn above the tile maps
+        Transform::from_xyz(0.0, 0.0, 1.0),
+        Visibility::Hidden,
+    ));
+}
+
+/**
+ * System that picks up new paths and moves the sprite along, jumping straight through portals and stairs
+ */
+fn walk(
+    time: Res<Time>,
+    mut walker: ResMut<Walker>,
+    pathfinder: Res<Pathfinder>,
+    current: Res<CurrentFloor>,
+    mut sprite: Single<(&mut Transform, &mut Visibility), With<WalkerSprite>>,
+    tilemap: Single<
+        (
+            &TilemapSize,
+            &TilemapGridSize,
+            &TilemapTileSize,
+            &TilemapType,
+            &TilemapAnchor,
+            &Transform,
+        ),
+        (With<ActiveFloor>, Without<WalkerSprite>),
+    >,
+) {
+    let (transform, visibility) = &mut *sprite;
+
+    if walker.route != pathfinder.path {
+        walker.route = pathfinder.path.clone();
+        walker.restart();
+    }
+
+    if !walker.enabled || walker.route.is_empty() {
+        **visibility = Visibility::Hidden;
+        return;
+    }
+
+    walker.progress += walker.speed * time.delta_secs();
+
+    // Move on through as many segments as this frame covers, stopping on the last tile
+    while walker.segment + 1 < walker.route.len() {
+        let (from, to) = (
+            walker.route[walker.segment],
+            walker.route[walker.segment + 1],
+        );
+        let length = if pathfinder.is_jump(to) {
+            0.0
+        } else {
+            tile_distance(from.pos, to.pos)
+        };
+
+        if walker.progress < length {
+            break;
+        }
+        walker.progress -= length;
+        walker.segment += 1;
+    }
+
+    let from = walker.route[walker.segment];
+    let position = match walker.route.get(walker.segment + 1) {
+        Some(&to) => {
+            let length = tile_distance(from.pos, to.pos);
+            tile_center(*tilemap, from.pos)
+                .lerp(tile_center(*tilemap, to.pos), walker.progress / length)
+        }
+        None => {
+            walker.progress = 0.0;
+            tile_center(*tilemap, from.pos)
+        }
+    };
+
+    transform.translation = position.extend(transform.translation.z);
+    **visibility = if from.floor == current.0 {
+        Visibility::Inherited
+    } else {
+        Visibility::Hidden
+    };
+}